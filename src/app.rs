@@ -0,0 +1,1119 @@
+use std::mem::discriminant;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use eframe::{App, Frame, NativeOptions};
+use egui::{Color32, ColorImage, CursorIcon, pos2, Rect, Response, RichText, Sense, Stroke, TextureHandle, vec2, ViewportBuilder};
+use image::{DynamicImage, GenericImageView, GrayImage, RgbaImage};
+use image::imageops::{resize, FilterType};
+use rfd::FileDialog;
+
+use pixel_sorting_rust::{EffectParams, histogram, MaskParams, palette, preset};
+use pixel_sorting_rust::histogram::AutoThresholdChoice;
+use pixel_sorting_rust::morphology::{MorphologyChoice, MorphologyStep};
+use pixel_sorting_rust::mask::{self, MaskCombineChoice, MaskFuncChoice, MaskLayer, MaskOverride, MaskRule};
+use pixel_sorting_rust::pixel::ColorValueChoice;
+use pixel_sorting_rust::pixel_generators::PixelAddChoice;
+use pixel_sorting_rust::sort_effect::{Progress, SortDirectionChoice, SortModeChoice, SortOrderChoice};
+
+// Longest side of the downscaled copy used for the live preview
+const PREVIEW_SIZE: u32 = 512;
+const PREVIEW_DEBOUNCE: Duration = Duration::from_millis(300);
+// Enough for every rule of a few layers, distance to color makes a new one for every picked color
+const HISTOGRAM_CACHE_SIZE: usize = 8;
+
+// Effect running on a background thread
+struct EffectJob {
+    progress: Arc<Progress>,
+    handle: JoinHandle<Option<DynamicImage>>,
+    started: Instant,
+    params: EffectParams,
+    // Where to save the result once it's done
+    export_path: Option<PathBuf>,
+}
+
+// Структура для хранения данных GUI
+struct MyApp {
+    params: EffectParams,
+    opened_image: Option<DynamicImage>,
+    result_image: Option<DynamicImage>,
+    result_params: Option<EffectParams>,
+    // Mask loaded from disk, replaces the threshold mask
+    loaded_mask: Option<GrayImage>,
+    // Brush strokes, painted on the downscaled copy and stretched to the full image when the effect runs
+    mask_override: Option<MaskOverride>,
+    // Share of the image covered by the mask and the settings it was measured with
+    mask_coverage: Option<(MaskParams, f64)>,
+    // Mask function histograms of the downscaled copy, most recently used last
+    histograms: Vec<(MaskFuncChoice, Vec<u32>)>,
+    auto_threshold: AutoThresholdChoice,
+    auto_coverage: f64,
+    // Color used by the "distance to color" sort key and mask function
+    reference_color: [u8; 3],
+    // Next click on the image picks the reference color
+    picking_color: bool,
+    // Number of colors taken from the image for the palette generator
+    palette_size: usize,
+    brush_radius: f32,
+    brush_hardness: f32,
+    brush_exclude: bool,
+    last_brush_pos: Option<(f32, f32)>,
+    loaded_texture: Option<TextureHandle>,
+    last_error: Option<String>,
+    is_error: bool,
+    is_mask_showed: bool,
+    show_settings: bool,
+    effect_job: Option<EffectJob>,
+    live_preview: bool,
+    preview_source: Option<RgbaImage>,
+    previewed_params: Option<EffectParams>,
+    params_changed_at: Option<Instant>,
+}
+
+impl Default for MyApp {
+    fn default() -> Self {
+        Self {
+            params: EffectParams::default(),
+            opened_image: None,
+            result_image: None,
+            result_params: None,
+            loaded_mask: None,
+            mask_override: None,
+            mask_coverage: None,
+            histograms: vec![],
+            auto_threshold: AutoThresholdChoice::Otsu,
+            auto_coverage: 0.3,
+            reference_color: [0, 128, 128],
+            picking_color: false,
+            palette_size: 8,
+            brush_radius: 30.0,
+            brush_hardness: 0.5,
+            brush_exclude: false,
+            last_brush_pos: None,
+            loaded_texture: None,
+            last_error: None,
+            is_error: true,
+            is_mask_showed: false,
+            show_settings: false,
+            effect_job: None,
+            live_preview: false,
+            preview_source: None,
+            previewed_params: None,
+            params_changed_at: None,
+        }
+    }
+}
+
+impl MyApp {
+    fn gen_mask(&self) -> GrayImage {
+        let image = self.opened_image.as_ref().unwrap().to_rgba8();
+        full_mask(&image, self.loaded_mask.clone(), self.mask_override.as_ref(), &self.params.mask)
+    }
+
+    // Measured on the downscaled copy, so it's cheap enough to keep up to date while the sliders move
+    fn update_mask_coverage(&mut self) {
+        if self.preview_source.is_none() || self.mask_coverage.as_ref().is_some_and(|(params, _)| *params == self.params.mask) {
+            return;
+        }
+        let mask = self.preview_mask(&self.preview_params().mask);
+        self.mask_coverage = Some((self.params.mask.clone(), histogram::mask_coverage(&mask)));
+    }
+
+    // The result and the preview no longer match the mask
+    fn mask_replaced(&mut self, ctx: &egui::Context) {
+        self.result_params = None;
+        self.previewed_params = None;
+        self.mask_coverage = None;
+        self.update_mask(ctx);
+    }
+
+    // Palette generator with the main colors of the opened image, black and white without one.
+    // The downscaled copy has the same colors and keeps the window responsive.
+    fn extract_palette(&mut self) {
+        let (colors, weights) = match &self.preview_source {
+            Some(image) => { palette::extract_palette(image, self.palette_size, self.params.seed) }
+            None => { (vec![[0, 0, 0], [255, 255, 255]], vec![]) }
+        };
+        let weighted = matches!(&self.params.pixel_add_choice, PixelAddChoice::Palette { weights, .. } if !weights.is_empty());
+        self.params.pixel_add_choice = PixelAddChoice::Palette { colors, weights: if weighted { weights } else { vec![] } };
+    }
+
+    fn palette_ui(&mut self, ui: &mut egui::Ui) {
+        let PixelAddChoice::Palette { colors, weights } = &mut self.params.pixel_add_choice else {
+            return;
+        };
+        ui.horizontal_wrapped(|ui| {
+            for [r, g, b] in colors.iter() {
+                let (rect, _) = ui.allocate_exact_size(vec2(16.0, 16.0), Sense::hover());
+                ui.painter().rect_filled(rect, 2.0, Color32::from_rgb(*r, *g, *b));
+            }
+        });
+
+        let mut weighted = !weights.is_empty();
+        let mut extract = false;
+        ui.horizontal(|ui| {
+            if ui.button("Load palette…").clicked() {
+                let file = FileDialog::new()
+                    .set_title("Load palette")
+                    .add_filter("Palette", &["gpl", "txt", "hex"])
+                    .pick_file();
+                if let Some(file) = file {
+                    match palette::load_palette(file.as_path()) {
+                        Ok(loaded) => {
+                            *colors = loaded;
+                            weights.clear();
+                        }
+                        Err(e) => {
+                            self.last_error = Some(e);
+                            self.is_error = true;
+                        }
+                    }
+                }
+            }
+            ui.add_space(10.0);
+            ui.add(egui::DragValue::new(&mut self.palette_size).clamp_range(1..=64).suffix(" colors"));
+            extract = ui.add_enabled(self.opened_image.is_some(), egui::Button::new("From image")).clicked();
+            ui.add_space(10.0);
+            ui.add_enabled(self.opened_image.is_some(), egui::Checkbox::new(&mut weighted, "Weight by frequency in the image"));
+        });
+
+        if extract {
+            self.extract_palette();
+        }
+        if let PixelAddChoice::Palette { colors, weights } = &mut self.params.pixel_add_choice {
+            match (&self.preview_source, weighted) {
+                (Some(image), true) if weights.len() != colors.len() => { *weights = palette::color_frequencies(image, colors) }
+                (_, false) => { weights.clear() }
+                _ => {}
+            }
+        }
+    }
+
+    // Points every "distance to color" choice at the new reference color
+    fn set_reference_color(&mut self, color: [u8; 3], ctx: &egui::Context) {
+        self.reference_color = color;
+        for key in [Some(&mut self.params.pixel_sort_choice), self.params.pixel_sort_tiebreak.as_mut()].into_iter().flatten() {
+            if let ColorValueChoice::DistanceTo(_) = key {
+                *key = ColorValueChoice::DistanceTo(color);
+            }
+        }
+        let layer_rules = self.params.mask.mask_layers.iter_mut().map(|layer| &mut layer.rule);
+        for rule in std::iter::once(&mut self.params.mask.mask_rule).chain(layer_rules) {
+            if let MaskFuncChoice::Color(ColorValueChoice::DistanceTo(_)) = rule.mask_func_choice {
+                rule.mask_func_choice = MaskFuncChoice::Color(ColorValueChoice::DistanceTo(color));
+            }
+        }
+        self.update_mask(ctx);
+    }
+
+    // Eyedropper, takes the color of the original image under the pointer
+    fn pick_color(&mut self, response: &Response, ctx: &egui::Context) {
+        let Some(pointer) = response.hover_pos() else {
+            return;
+        };
+        let response = response.clone().on_hover_cursor(CursorIcon::Crosshair);
+        if !response.clicked() {
+            return;
+        }
+        let image = self.opened_image.as_ref().unwrap();
+        let relative = (pointer - response.rect.min) / response.rect.size();
+        let x = ((relative.x * image.width() as f32) as u32).min(image.width() - 1);
+        let y = ((relative.y * image.height() as f32) as u32).min(image.height() - 1);
+        let [r, g, b, _] = image.get_pixel(x, y).0;
+        self.picking_color = false;
+        self.set_reference_color([r, g, b], ctx);
+    }
+
+    // Paints on the mask along the pointer path while it's dragged over the shown mask
+    fn paint_mask(&mut self, response: &Response, ctx: &egui::Context) {
+        let Some(pointer) = response.interact_pointer_pos().filter(|_| response.is_pointer_button_down_on()) else {
+            self.last_brush_pos = None;
+            return;
+        };
+        // Strokes go on the downscaled copy, the brush radius is in pixels of the full image
+        let (width, height) = self.preview_source.as_ref().unwrap().dimensions();
+        let radius = self.brush_radius * width as f32 / self.opened_image.as_ref().unwrap().width() as f32;
+        let relative = (pointer - response.rect.min) / response.rect.size();
+        let position = (relative.x * width as f32, relative.y * height as f32);
+        if self.last_brush_pos == Some(position) {
+            return;
+        }
+
+        let mask_override = self.mask_override.get_or_insert_with(|| MaskOverride::new(width, height));
+        let from = self.last_brush_pos.unwrap_or(position);
+        let distance = ((position.0 - from.0).powi(2) + (position.1 - from.1).powi(2)).sqrt();
+        let steps = (distance / (radius / 4.0).max(1.0)).ceil().max(1.0) as usize;
+        for step in 1..=steps {
+            let t = step as f32 / steps as f32;
+            mask_override.stamp(from.0 + (position.0 - from.0) * t, from.1 + (position.1 - from.1) * t, radius, self.brush_hardness, self.brush_exclude);
+        }
+        self.last_brush_pos = Some(position);
+        self.mask_replaced(ctx);
+    }
+
+    // The shown mask is the one of the downscaled copy, so it keeps up with painting and the sliders
+    fn update_mask(&mut self, ctx: &egui::Context) {
+        if self.is_mask_showed && self.preview_source.is_some() {
+            let mask = self.preview_mask(&self.preview_params().mask);
+            self.mask_coverage = Some((self.params.mask.clone(), histogram::mask_coverage(&mask)));
+            self.loaded_texture = Some(load_texture_from_dynamic_image(&DynamicImage::ImageLuma8(mask), ctx));
+        }
+    }
+
+    fn start_effect(&mut self, export_path: Option<PathBuf>) {
+        let image = self.opened_image.clone().unwrap().to_rgba8();
+        let params = self.params.clone();
+        let job_params = params.clone();
+        let progress = Arc::new(Progress::default());
+        let job_progress = progress.clone();
+        let loaded_mask = self.loaded_mask.clone();
+        let mask_override = self.mask_override.clone();
+        let handle = thread::spawn(move || {
+            let mask = full_mask(&image, loaded_mask, mask_override.as_ref(), &job_params.mask);
+            pixel_sorting_rust::apply_with_progress(&image, &mask, &job_params, &job_progress).map(DynamicImage::ImageRgba8)
+        });
+        self.effect_job = Some(EffectJob { progress, handle, started: Instant::now(), params, export_path });
+    }
+
+    fn save_result(&mut self, path: &str) {
+        match self.result_image.clone().unwrap().save(path) {
+            Ok(_) => {
+                self.last_error = Some(format!("File was saved to {}", path));
+                self.is_error = false
+            }
+            Err(e) => {
+                self.last_error = Some(e.to_string());
+                self.is_error = true;
+            }
+        }
+    }
+
+    // Settings used on the downscaled copy
+    fn preview_params(&self) -> EffectParams {
+        let scale = self.preview_source.as_ref().unwrap().width() as f64 / self.opened_image.as_ref().unwrap().width() as f64;
+        let mut params = self.params.clone();
+        // Span lengths are in pixels, so they have to shrink together with the image
+        for length in [&mut params.sort_options.max_span_length, &mut params.sort_options.min_span_length] {
+            if *length > 0 {
+                *length = ((*length as f64 * scale).round() as usize).max(1);
+            }
+        }
+        for step in &mut params.mask.mask_morphology {
+            step.radius = ((step.radius as f64 * scale).round() as u32).max(1);
+        }
+        if let PixelAddChoice::PerlinNoise { scale: noise_scale } = &mut params.pixel_add_choice {
+            *noise_scale *= scale;
+        }
+        params.mask.mask_min_component = (params.mask.mask_min_component as f64 * scale * scale).round() as u32;
+        params.mask.mask_blur *= scale as f32;
+        params
+    }
+
+    fn preview_mask(&self, params: &MaskParams) -> GrayImage {
+        let source = self.preview_source.as_ref().unwrap();
+        let mut mask = match &self.loaded_mask {
+            Some(mask) => { resize(mask, source.width(), source.height(), FilterType::Triangle) }
+            None => { pixel_sorting_rust::gen_mask(source, params) }
+        };
+        if let Some(mask_override) = &self.mask_override {
+            mask_override.apply(&mut mask);
+        }
+        if self.loaded_mask.is_some() || self.mask_override.is_some() {
+            pixel_sorting_rust::finish_mask(source, &mut mask, params);
+        }
+        mask
+    }
+
+    // Re-runs the effect on the downscaled copy once the settings stop changing
+    fn update_preview(&mut self, ctx: &egui::Context) {
+        if !self.live_preview || self.is_mask_showed || self.effect_job.is_some() || self.preview_source.is_none() {
+            return;
+        }
+        if self.previewed_params.as_ref() == Some(&self.params) {
+            self.params_changed_at = None;
+            return;
+        }
+
+        let changed_at = *self.params_changed_at.get_or_insert_with(Instant::now);
+        if changed_at.elapsed() < PREVIEW_DEBOUNCE {
+            ctx.request_repaint_after(PREVIEW_DEBOUNCE - changed_at.elapsed());
+            return;
+        }
+
+        let params = self.preview_params();
+        let mask = self.preview_mask(&params.mask);
+        let source = self.preview_source.as_ref().unwrap();
+        let preview = pixel_sorting_rust::apply_with_progress(source, &mask, &params, &Progress::default()).unwrap();
+        self.loaded_texture = Some(load_texture_from_dynamic_image(&DynamicImage::ImageRgba8(preview), ctx));
+        self.previewed_params = Some(self.params.clone());
+        self.params_changed_at = None;
+    }
+
+    fn cancel_effect(&mut self) {
+        if let Some(job) = &self.effect_job {
+            job.progress.cancel();
+        }
+    }
+
+    fn poll_effect(&mut self, ctx: &egui::Context) {
+        let Some(job) = &self.effect_job else { return };
+        if !job.handle.is_finished() {
+            ctx.request_repaint_after(Duration::from_millis(50));
+            return;
+        }
+
+        let job = self.effect_job.take().unwrap();
+        match job.handle.join() {
+            Ok(Some(result)) if !job.progress.is_cancelled() => {
+                self.loaded_texture = Some(load_texture_from_dynamic_image(&result, ctx));
+                self.result_image = Some(result);
+                self.previewed_params = Some(job.params.clone());
+                self.result_params = Some(job.params);
+                self.is_mask_showed = false;
+                self.last_error = Some(format!("Time elapsed is: {:?}", job.started.elapsed()));
+                self.is_error = false;
+                if let Some(path) = job.export_path {
+                    self.save_result(&path.to_string_lossy());
+                }
+            }
+            Ok(_) => {
+                self.last_error = Some("Effect was cancelled".to_string());
+                self.is_error = false;
+            }
+            Err(_) => {
+                self.last_error = Some("Effect failed".to_string());
+                self.is_error = true;
+            }
+        }
+    }
+}
+
+impl App for MyApp {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut Frame) {
+        self.poll_effect(ctx);
+
+        egui::CentralPanel::default().show(ctx, |ui| {
+            egui::menu::bar(ui, |ui| {
+                ui.menu_button("File", |ui| {
+                    if ui.button("Open").clicked() {
+                        let file = FileDialog::new().pick_file();
+                        if let Some(file) = file {
+                            match image::open(file.as_path()) {
+                                Ok(i) => {
+                                    self.cancel_effect();
+                                    self.loaded_texture = Some(load_texture_from_dynamic_image(&i, ctx));
+                                    self.preview_source = Some(i.thumbnail(PREVIEW_SIZE, PREVIEW_SIZE).to_rgba8());
+                                    self.previewed_params = None;
+                                    self.opened_image = Some(i.clone());
+                                    self.loaded_mask = None;
+                                    self.mask_override = None;
+                                    self.mask_coverage = None;
+                                    self.histograms.clear();
+                                    self.is_mask_showed = false;
+                                    self.result_image = None;
+                                }
+                                Err(e) => {
+                                    self.last_error = Some(e.to_string());
+                                    self.is_error = true;
+                                }
+                            }
+                        }
+                    }
+                    if ui.button("Close file").clicked() {
+                        self.cancel_effect();
+                        self.loaded_texture = None;
+                        self.opened_image = None;
+                        self.preview_source = None;
+                        self.loaded_mask = None;
+                        self.mask_override = None;
+                        self.mask_coverage = None;
+                        self.histograms.clear();
+                        self.is_mask_showed = false;
+                        self.result_image = None;
+                        self.show_settings = false;
+                    }
+                    ui.separator();
+                    if ui.add_enabled(self.opened_image.is_some(), egui::Button::new("Load mask…")).clicked() {
+                        let file = FileDialog::new().set_title("Load mask").pick_file();
+                        if let Some(file) = file {
+                            let (width, height) = (self.opened_image.as_ref().unwrap().width(), self.opened_image.as_ref().unwrap().height());
+                            match mask::load_mask(file.as_path(), width, height) {
+                                Ok(m) => {
+                                    self.loaded_mask = Some(m);
+                                    self.mask_replaced(ctx);
+                                }
+                                Err(e) => {
+                                    self.last_error = Some(e);
+                                    self.is_error = true;
+                                }
+                            }
+                        }
+                    }
+                    if self.loaded_mask.is_some() && ui.button("Clear loaded mask").clicked() {
+                        self.loaded_mask = None;
+                        self.mask_replaced(ctx);
+                    }
+                    if ui.add_enabled(self.opened_image.is_some(), egui::Button::new("Export mask…")).clicked() {
+                        let file = FileDialog::new()
+                            .add_filter("PNG", &["png"])
+                            .set_file_name("mask.png")
+                            .set_title("Export mask")
+                            .save_file();
+                        if let Some(file) = file {
+                            match self.gen_mask().save(file.as_path()) {
+                                Ok(_) => {
+                                    self.last_error = Some(format!("Mask was saved to {}", file.display()));
+                                    self.is_error = false;
+                                }
+                                Err(e) => {
+                                    self.last_error = Some(e.to_string());
+                                    self.is_error = true;
+                                }
+                            }
+                        }
+                    }
+                    ui.separator();
+                    if ui.button("Save preset…").clicked() {
+                        let file = FileDialog::new()
+                            .add_filter("Preset", &["json"])
+                            .set_file_name("preset.json")
+                            .set_title("Save preset")
+                            .save_file();
+                        if let Some(file) = file {
+                            match preset::save_preset(&self.params, file.as_path()) {
+                                Ok(_) => {
+                                    self.last_error = Some(format!("Preset was saved to {}", file.display()));
+                                    self.is_error = false;
+                                }
+                                Err(e) => {
+                                    self.last_error = Some(e);
+                                    self.is_error = true;
+                                }
+                            }
+                        }
+                    }
+                    if ui.button("Load preset…").clicked() {
+                        let file = FileDialog::new()
+                            .add_filter("Preset", &["json"])
+                            .set_title("Load preset")
+                            .pick_file();
+                        if let Some(file) = file {
+                            match preset::load_preset(file.as_path()) {
+                                Ok(params) => {
+                                    self.params = params;
+                                    self.update_mask(ctx);
+                                }
+                                Err(e) => {
+                                    self.last_error = Some(e);
+                                    self.is_error = true;
+                                }
+                            }
+                        }
+                    }
+                    ui.menu_button("Built-in presets", |ui| {
+                        for (name, params) in preset::builtin_presets() {
+                            if ui.button(name).clicked() {
+                                self.params = params;
+                                self.update_mask(ctx);
+                                ui.close_menu();
+                            }
+                        }
+                    });
+                });
+                ui.menu_button("Settings", |ui| {
+                    if ui.button(if !self.show_settings { "Open Effect Settings" } else { "Close Effect Settings" }).clicked() {
+                        self.show_settings = !self.show_settings;
+                    }
+                })
+            });
+
+            ui.separator();
+
+            ui.horizontal(|ui| {
+                ui.heading("Pixel Sort Effect");
+
+                if self.last_error.is_some() && ui.colored_label(
+                    if self.is_error { Color32::RED } else { Color32::GREEN },
+                    self.last_error.clone().unwrap().as_str(),
+                ).clicked() {
+                    self.last_error = None;
+                }
+            });
+
+
+            if self.show_settings {
+                self.update_mask_coverage();
+                egui::Window::new("Effect Settings")
+                    .show(ctx, |ui| {
+                        ui.horizontal(|ui| {
+                            ui.label("Reference color");
+                            let mut color = self.reference_color;
+                            if ui.color_edit_button_srgb(&mut color).changed() {
+                                self.set_reference_color(color, ctx);
+                            }
+                            ui.toggle_value(&mut self.picking_color, "Pick from image")
+                                .on_hover_text("Used by the \"Distance to color\" sort key and mask function");
+                        });
+                        ui.add_space(10.0);
+
+                        ui.label(if self.loaded_mask.is_some() { "Mask Settings (a loaded mask is used instead)" } else { "Mask Settings" });
+                        ui.group(|ui| {
+                            ui.horizontal(|ui| {
+                                egui::ComboBox::new("auto_threshold", "Auto threshold")
+                                    .selected_text(format!("{:?}", self.auto_threshold))
+                                    .show_ui(ui, |ui| {
+                                        ui.selectable_value(&mut self.auto_threshold, AutoThresholdChoice::Otsu, "Otsu");
+                                        ui.selectable_value(&mut self.auto_threshold, AutoThresholdChoice::Coverage, "Coverage");
+                                    });
+                                if self.auto_threshold == AutoThresholdChoice::Coverage {
+                                    ui.add(egui::Slider::new(&mut self.auto_coverage, 0.0..=1.0)
+                                        .custom_formatter(|v, _| format!("{:.0}%", v * 100.0))
+                                        .text("of pixels"));
+                                }
+                                if let Some((_, coverage)) = &self.mask_coverage {
+                                    ui.add_space(20.0);
+                                    ui.label(format!("Masked: {:.1}% of pixels", coverage * 100.0));
+                                }
+                            });
+                            let auto = (self.auto_threshold.clone(), self.auto_coverage);
+                            let histogram = self.preview_source.as_ref().map(|image| cached_histogram(&mut self.histograms, image, &self.params.mask.mask_rule.mask_func_choice));
+                            if mask_rule_ui(ui, "mask_rule", &mut self.params.mask.mask_rule, histogram, &auto, self.reference_color) { self.update_mask(ctx) }
+                            ui.horizontal(|ui| {
+                                let (mask_range_from, mask_range_to) = self.params.mask.mask_rule.mask_func_choice.get_range();
+                                let feather_slider = ui.add(egui::Slider::new(&mut self.params.mask.mask_feather, 0.0..=(mask_range_to - mask_range_from) / 4.0).text("Feather (0 = hard edges)"));
+                                ui.add_space(5.0);
+                                let blur_slider = ui.add(egui::Slider::new(&mut self.params.mask.mask_blur, 0.0..=20.0).text("Mask blur"));
+                                if feather_slider.changed() || blur_slider.changed() { self.update_mask(ctx) }
+                            });
+                            if ui.checkbox(&mut self.params.mask.mask_exclude_transparent, "Leave transparent pixels in place").changed() { self.update_mask(ctx) }
+                            let mut cleanup_changed = false;
+                            let mut removed_step: Option<usize> = None;
+                            for (i, step) in self.params.mask.mask_morphology.iter_mut().enumerate() {
+                                ui.horizontal(|ui| {
+                                    egui::ComboBox::new(("mask_morphology", i), "Cleanup")
+                                        .selected_text(format!("{:?}", step.operation))
+                                        .show_ui(ui, |ui| {
+                                            for (choice, name) in [
+                                                (MorphologyChoice::Dilate, "Dilate"),
+                                                (MorphologyChoice::Erode, "Erode"),
+                                                (MorphologyChoice::Open, "Open"),
+                                                (MorphologyChoice::Close, "Close"),
+                                            ] {
+                                                cleanup_changed |= ui.selectable_value(&mut step.operation, choice, name).changed();
+                                            }
+                                        });
+                                    cleanup_changed |= ui.add(egui::Slider::new(&mut step.radius, 1..=20).text("Radius")).changed();
+                                    if ui.button("Remove").clicked() { removed_step = Some(i) }
+                                });
+                            }
+                            if let Some(i) = removed_step {
+                                self.params.mask.mask_morphology.remove(i);
+                                cleanup_changed = true;
+                            }
+                            ui.horizontal(|ui| {
+                                if ui.button("Add cleanup step").clicked() {
+                                    self.params.mask.mask_morphology.push(MorphologyStep { operation: MorphologyChoice::Close, radius: 1 });
+                                    cleanup_changed = true;
+                                }
+                                ui.add_space(5.0);
+                                cleanup_changed |= ui.add(egui::Slider::new(&mut self.params.mask.mask_min_component, 0..=1000).logarithmic(true).text("Min area (px)")).changed();
+                            });
+                            if cleanup_changed { self.update_mask(ctx) }
+
+                            let mut layers_changed = false;
+                            let mut removed_layer: Option<usize> = None;
+                            for (i, layer) in self.params.mask.mask_layers.iter_mut().enumerate() {
+                                ui.separator();
+                                ui.horizontal(|ui| {
+                                    egui::ComboBox::new(("mask_layer_combine", i), "with the mask above")
+                                        .selected_text(format!("{:?}", layer.combine))
+                                        .show_ui(ui, |ui| {
+                                            for (choice, name) in [
+                                                (MaskCombineChoice::And, "AND"),
+                                                (MaskCombineChoice::Or, "OR"),
+                                                (MaskCombineChoice::Xor, "XOR"),
+                                                (MaskCombineChoice::Subtract, "SUBTRACT"),
+                                                (MaskCombineChoice::Min, "Min"),
+                                                (MaskCombineChoice::Max, "Max"),
+                                            ] {
+                                                layers_changed |= ui.selectable_value(&mut layer.combine, choice, name).changed();
+                                            }
+                                        });
+                                    if ui.button("Remove").clicked() { removed_layer = Some(i) }
+                                });
+                                let histogram = self.preview_source.as_ref().map(|image| cached_histogram(&mut self.histograms, image, &layer.rule.mask_func_choice));
+                                layers_changed |= mask_rule_ui(ui, ("mask_layer_rule", i), &mut layer.rule, histogram, &auto, self.reference_color);
+                            }
+                            if let Some(i) = removed_layer {
+                                self.params.mask.mask_layers.remove(i);
+                                layers_changed = true;
+                            }
+                            if ui.button("Add mask layer").clicked() {
+                                self.params.mask.mask_layers.push(MaskLayer { combine: MaskCombineChoice::And, rule: MaskRule::default() });
+                                layers_changed = true;
+                            }
+                            if layers_changed { self.update_mask(ctx) }
+                        });
+
+                        ui.add_space(10.0);
+
+                        ui.label("Pixel Addition Settings");
+                        ui.group(|ui| {
+                            ui.horizontal(|ui| {
+                                ui.add(egui::Slider::new(&mut self.params.random_prob, 0.0..=1.0).text("Pixel addition probability"));
+                                ui.checkbox(&mut self.params.pixel_add_keep_alpha, "Keep original alpha");
+                                ui.add_space(20.0);
+                                let choices = pixel_add_choices();
+                                let selected = choices.iter().find(|(choice, _)| discriminant(choice) == discriminant(&self.params.pixel_add_choice)).map_or("", |(_, name)| name);
+                                egui::ComboBox::from_label("Pixel Addition Function")
+                                    .selected_text(selected)
+                                    .show_ui(ui, |ui| {
+                                        for (choice, name) in choices {
+                                            let is_selected = discriminant(&choice) == discriminant(&self.params.pixel_add_choice);
+                                            // Reselecting keeps the settings of the current one
+                                            if ui.selectable_label(is_selected, name).clicked() && !is_selected {
+                                                self.params.pixel_add_choice = choice;
+                                                if let PixelAddChoice::Palette { .. } = self.params.pixel_add_choice {
+                                                    self.extract_palette();
+                                                }
+                                            }
+                                        }
+                                    })
+                            });
+                            match &mut self.params.pixel_add_choice {
+                                PixelAddChoice::Palette { .. } => { self.palette_ui(ui) }
+                                PixelAddChoice::LinearGradient { start, end, angle } => {
+                                    ui.horizontal(|ui| {
+                                        ui.color_edit_button_srgb(start);
+                                        ui.label("to");
+                                        ui.color_edit_button_srgb(end);
+                                        ui.add_space(10.0);
+                                        ui.add(egui::Slider::new(angle, 0.0..=360.0).suffix("°").text("Angle"));
+                                    });
+                                }
+                                PixelAddChoice::RadialGradient { center, edge } => {
+                                    ui.horizontal(|ui| {
+                                        ui.label("Center");
+                                        ui.color_edit_button_srgb(center);
+                                        ui.label("Edge");
+                                        ui.color_edit_button_srgb(edge);
+                                    });
+                                }
+                                PixelAddChoice::PerlinNoise { scale } => { ui.add(egui::Slider::new(scale, 2.0..=512.0).logarithmic(true).text("Noise scale (px)")); }
+                                PixelAddChoice::Jitter { amount } => { ui.add(egui::Slider::new(amount, 0..=255).text("Jitter amount")); }
+                                _ => {}
+                            }
+                        });
+
+                        ui.add_space(10.0);
+
+                        ui.label("Sorting Settings");
+                        ui.group(|ui| {
+                            ui.horizontal(|ui| {
+                                egui::ComboBox::from_label("Pixel Sorting Key Function")
+                                    .selected_text(format!("{:?}", self.params.pixel_sort_choice))
+                                    .show_ui(ui, |ui| {
+                                        for (choice, name) in color_values(self.reference_color) {
+                                            let is_selected = discriminant(&choice) == discriminant(&self.params.pixel_sort_choice);
+                                            // Reselecting keeps the bucket or level count of the current one
+                                            if ui.selectable_label(is_selected, name).clicked() && !is_selected {
+                                                self.params.pixel_sort_choice = choice;
+                                            }
+                                        }
+                                    });
+                                color_value_steps_ui(ui, &mut self.params.pixel_sort_choice);
+                            });
+                            ui.horizontal(|ui| {
+                                egui::ComboBox::from_label("Then by (equal keys)")
+                                    .selected_text(self.params.pixel_sort_tiebreak.as_ref().map_or("None".to_string(), |key| format!("{:?}", key)))
+                                    .show_ui(ui, |ui| {
+                                        ui.selectable_value(&mut self.params.pixel_sort_tiebreak, None, "None");
+                                        for (choice, name) in color_values(self.reference_color) {
+                                            let is_selected = self.params.pixel_sort_tiebreak.as_ref().is_some_and(|key| discriminant(key) == discriminant(&choice));
+                                            if ui.selectable_label(is_selected, name).clicked() && !is_selected {
+                                                self.params.pixel_sort_tiebreak = Some(choice);
+                                            }
+                                        }
+                                    });
+                                if let Some(key) = &mut self.params.pixel_sort_tiebreak {
+                                    color_value_steps_ui(ui, key);
+                                }
+                            });
+                            egui::ComboBox::from_label("Sorting mode")
+                                .selected_text(format!("{:?}", self.params.sort_options.mode))
+                                .show_ui(ui, |ui| {
+                                    ui.selectable_value(&mut self.params.sort_options.mode, SortModeChoice::Interval, "Each masked interval");
+                                    ui.selectable_value(&mut self.params.sort_options.mode, SortModeChoice::WholeRow, "Whole line");
+                                });
+                            ui.horizontal(|ui| {
+                                egui::ComboBox::from_label("Sorting direction")
+                                    .selected_text(format!("{:?}", self.params.sort_options.direction))
+                                    .show_ui(ui, |ui| {
+                                        ui.selectable_value(&mut self.params.sort_options.direction, SortDirectionChoice::Horizontal, "Horizontal");
+                                        ui.selectable_value(&mut self.params.sort_options.direction, SortDirectionChoice::Vertical, "Vertical");
+                                        ui.selectable_value(&mut self.params.sort_options.direction, SortDirectionChoice::DiagonalDown, "Diagonal (top-left to bottom-right)");
+                                        ui.selectable_value(&mut self.params.sort_options.direction, SortDirectionChoice::DiagonalUp, "Diagonal (bottom-left to top-right)");
+                                        ui.selectable_value(&mut self.params.sort_options.direction, SortDirectionChoice::Angle, "Custom angle");
+                                    });
+                                if self.params.sort_options.direction == SortDirectionChoice::Angle {
+                                    ui.add_space(20.0);
+                                    ui.add(egui::Slider::new(&mut self.params.sort_options.angle, 0.0..=360.0).suffix("°").text("Angle"));
+                                }
+                            });
+                            ui.horizontal(|ui| {
+                                egui::ComboBox::from_label("Sorting order")
+                                    .selected_text(format!("{:?}", self.params.sort_options.order))
+                                    .show_ui(ui, |ui| {
+                                        ui.selectable_value(&mut self.params.sort_options.order, SortOrderChoice::Ascending, "Ascending");
+                                        ui.selectable_value(&mut self.params.sort_options.order, SortOrderChoice::Descending, "Descending");
+                                        ui.selectable_value(&mut self.params.sort_options.order, SortOrderChoice::RandomPerSpan, "Random per span");
+                                    });
+                            });
+                            ui.horizontal(|ui| {
+                                ui.add(egui::DragValue::new(&mut self.params.sort_options.max_span_length).prefix("Max span length: "));
+                                ui.label("(0 = unlimited)");
+                                if self.params.sort_options.max_span_length > 0 {
+                                    ui.add_space(20.0);
+                                    ui.checkbox(&mut self.params.sort_options.random_span_length, "Random span length");
+                                    if self.params.sort_options.random_span_length {
+                                        ui.add(egui::DragValue::new(&mut self.params.sort_options.min_span_length).clamp_range(1..=self.params.sort_options.max_span_length).prefix("Min: "));
+                                    }
+                                }
+                            });
+                        });
+
+                        ui.add_space(10.0);
+
+                        ui.label("Randomness");
+                        ui.group(|ui| {
+                            ui.horizontal(|ui| {
+                                ui.add(egui::DragValue::new(&mut self.params.seed).prefix("Seed: "));
+                                if ui.button("New seed").clicked() {
+                                    self.params.seed = rand::random();
+                                }
+                            });
+                        });
+                    });
+            }
+
+            ui.add_space(20.0);
+
+            ui.horizontal(|ui| {
+                let mask_check = ui.checkbox(&mut self.is_mask_showed, "Show mask");
+
+                if mask_check.changed() {
+                    if self.is_mask_showed {
+                        if self.opened_image.is_none() {
+                            self.last_error = Some("Image is not loaded".to_string());
+                            self.is_error = true;
+                        } else {
+                            self.update_mask(ctx);
+                        }
+                    } else if self.live_preview && self.result_params.as_ref() != Some(&self.params) {
+                        self.previewed_params = None;
+                    } else if self.result_image.is_some() {
+                        self.loaded_texture = Some(load_texture_from_dynamic_image(&self.result_image.clone().unwrap(), ctx));
+                    } else if self.opened_image.is_some() {
+                        self.loaded_texture = Some(load_texture_from_dynamic_image(&self.opened_image.clone().unwrap(), ctx));
+                    } else {
+                        self.loaded_texture = None
+                    }
+                }
+
+                if let Some(job) = &self.effect_job {
+                    ui.add(egui::ProgressBar::new(job.progress.fraction()).show_percentage().desired_width(200.0));
+                    if ui.add_enabled(!job.progress.is_cancelled(), egui::Button::new("Cancel")).clicked() {
+                        self.cancel_effect();
+                    }
+                } else if ui.button("Apply effect").clicked() {
+                    if self.opened_image.is_none() {
+                        self.last_error = Some("Image is not loaded".to_string());
+                        self.is_error = true;
+                    } else {
+                        self.start_effect(None);
+                    }
+                }
+
+                ui.add_space(10.0);
+                if ui.checkbox(&mut self.live_preview, "Live preview").changed() {
+                    self.previewed_params = None;
+                    if !self.live_preview && !self.is_mask_showed {
+                        if let Some(image) = self.result_image.as_ref().or(self.opened_image.as_ref()) {
+                            self.loaded_texture = Some(load_texture_from_dynamic_image(image, ctx));
+                        }
+                    }
+                }
+
+                if self.result_image.is_some() || (self.live_preview && self.opened_image.is_some()) {
+                    ui.add_space(10.0);
+
+                    if ui.add_enabled(self.effect_job.is_none(), egui::Button::new(RichText::new("Export result").color(Color32::GREEN))).clicked() {
+                        let file = FileDialog::new()
+                            .set_file_name("result.png")
+                            .set_title("Export result")
+                            .save_file();
+                        if let Some(file) = file {
+                            // The preview is low resolution, so render the full image first if it's out of date
+                            if self.result_image.is_some() && (!self.live_preview || self.result_params.as_ref() == Some(&self.params)) {
+                                self.save_result(&file.as_path().to_string_lossy());
+                            } else {
+                                self.start_effect(Some(file));
+                            }
+                        }
+                    }
+                }
+            });
+
+            ui.separator();
+            if self.is_mask_showed && self.opened_image.is_some() {
+                ui.horizontal(|ui| {
+                    ui.label("Brush:");
+                    ui.selectable_value(&mut self.brush_exclude, false, "Include");
+                    ui.selectable_value(&mut self.brush_exclude, true, "Exclude");
+                    ui.add(egui::Slider::new(&mut self.brush_radius, 1.0..=300.0).text("Radius"));
+                    ui.add(egui::Slider::new(&mut self.brush_hardness, 0.0..=1.0).text("Hardness"));
+                    if ui.add_enabled(self.mask_override.is_some(), egui::Button::new("Clear strokes")).clicked() {
+                        self.mask_override = None;
+                        self.mask_replaced(ctx);
+                    }
+                });
+            }
+
+            if self.loaded_texture.is_none() {
+                ui.label("Image is not loaded.");
+            } else {
+                let response = ui.add(
+                    egui::Image::new((self.loaded_texture.clone().unwrap().id(), self.loaded_texture.clone().unwrap().size_vec2()))
+                        .max_size(ui.available_size())
+                        .sense(Sense::click_and_drag())
+                );
+                if self.picking_color && self.opened_image.is_some() {
+                    self.pick_color(&response, ctx);
+                } else if self.is_mask_showed && self.opened_image.is_some() {
+                    self.paint_mask(&response, ctx);
+                }
+            }
+
+            self.update_preview(ctx);
+
+            // ctx.send_viewport_cmd(ViewportCommand::Title(format!("Pixel Sort Effect {:?}", ctx.input(|i| (i.screen_rect.width(), i.screen_rect.height())))));
+        });
+    }
+}
+
+pub fn run() {
+    let options = NativeOptions {
+        viewport: ViewportBuilder {
+            maximize_button: Some(false),
+            resizable: Some(true),
+            min_inner_size: Some(vec2(800.0, 600.0)),
+            inner_size: Some(vec2(800.0, 600.0)),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    eframe::run_native(
+        "Pixel Sort Effect",
+        options,
+        Box::new(|_cc| Box::new(MyApp::default())),
+    ).unwrap();
+}
+
+// Pixel addition functions with their default settings
+fn pixel_add_choices() -> Vec<(PixelAddChoice, &'static str)> {
+    vec![
+        (PixelAddChoice::RandomPixel, "Random Pixel"),
+        (PixelAddChoice::RandomRedShade, "Random Red Shade"),
+        (PixelAddChoice::RandomBlueShade, "Random Blue Shade"),
+        (PixelAddChoice::RandomGreenShade, "Random Green Shade"),
+        (PixelAddChoice::Black, "Just black"),
+        (PixelAddChoice::Palette { colors: vec![], weights: vec![] }, "Palette"),
+        (PixelAddChoice::LinearGradient { start: [0, 0, 0], end: [255, 255, 255], angle: 0.0 }, "Linear gradient"),
+        (PixelAddChoice::RadialGradient { center: [255, 255, 255], edge: [0, 0, 0] }, "Radial gradient"),
+        (PixelAddChoice::PerlinNoise { scale: 64.0 }, "Perlin noise"),
+        (PixelAddChoice::Jitter { amount: 32 }, "Jittered original"),
+        (PixelAddChoice::Inverted, "Inverted original"),
+        (PixelAddChoice::ChannelRotated, "Channel-rotated original"),
+    ]
+}
+
+// Sort keys and mask functions in the order they're listed in the combo boxes
+fn color_values(reference_color: [u8; 3]) -> Vec<(ColorValueChoice, &'static str)> {
+    vec![
+        (ColorValueChoice::Hue, "Hue"),
+        (ColorValueChoice::QuantizedHue { buckets: 3 }, "Quantized hue"),
+        (ColorValueChoice::Luminance, "Luminance"),
+        (ColorValueChoice::PosterizedLuminance { levels: 4 }, "Posterized luminance"),
+        (ColorValueChoice::ColorSum, "Sum of colors"),
+        (ColorValueChoice::Red, "Red channel"),
+        (ColorValueChoice::Green, "Green channel"),
+        (ColorValueChoice::Blue, "Blue channel"),
+        (ColorValueChoice::Alpha, "Alpha (opacity)"),
+        (ColorValueChoice::HslSaturation, "Saturation (HSL)"),
+        (ColorValueChoice::HsvSaturation, "Saturation (HSV)"),
+        (ColorValueChoice::HsvValue, "Value (HSV)"),
+        (ColorValueChoice::LabLightness, "Lightness (Lab L*)"),
+        (ColorValueChoice::LabA, "Green–red (Lab a*)"),
+        (ColorValueChoice::LabB, "Blue–yellow (Lab b*)"),
+        (ColorValueChoice::OklabLightness, "Lightness (OKLab)"),
+        (ColorValueChoice::OklchChroma, "Chroma (OKLCH)"),
+        (ColorValueChoice::OklchHue, "Hue (OKLCH)"),
+        (ColorValueChoice::DistanceTo(reference_color), "Distance to color"),
+    ]
+}
+
+fn mask_funcs(reference_color: [u8; 3]) -> Vec<(MaskFuncChoice, &'static str)> {
+    let mut funcs: Vec<(MaskFuncChoice, &'static str)> = color_values(reference_color).into_iter().map(|(value, name)| (MaskFuncChoice::Color(value), name)).collect();
+    funcs.push((MaskFuncChoice::SobelEdges, "Edges (Sobel)"));
+    funcs.push((MaskFuncChoice::ScharrEdges, "Edges (Scharr)"));
+    funcs
+}
+
+// Same combo box entry, whatever the bucket count or color
+fn same_mask_func(a: &MaskFuncChoice, b: &MaskFuncChoice) -> bool {
+    match (a, b) {
+        (MaskFuncChoice::Color(a), MaskFuncChoice::Color(b)) => { discriminant(a) == discriminant(b) }
+        _ => { discriminant(a) == discriminant(b) }
+    }
+}
+
+// Bucket or level count of the quantized values, returns true when it was changed
+fn color_value_steps_ui(ui: &mut egui::Ui, value: &mut ColorValueChoice) -> bool {
+    match value {
+        ColorValueChoice::QuantizedHue { buckets } => { ui.add(egui::DragValue::new(buckets).clamp_range(2..=360).suffix(" buckets")).changed() }
+        ColorValueChoice::PosterizedLuminance { levels } => { ui.add(egui::DragValue::new(levels).clamp_range(2..=256).suffix(" levels")).changed() }
+        _ => { false }
+    }
+}
+
+// Histogram for a mask function, the least recently used one is dropped when the cache is full
+fn cached_histogram<'a>(histograms: &'a mut Vec<(MaskFuncChoice, Vec<u32>)>, image: &RgbaImage, mask_func_choice: &MaskFuncChoice) -> &'a [u32] {
+    let entry = match histograms.iter().position(|(choice, _)| choice == mask_func_choice) {
+        Some(index) => { histograms.remove(index) }
+        None => { (mask_func_choice.clone(), histogram::histogram(image, mask_func_choice)) }
+    };
+    if histograms.len() >= HISTOGRAM_CACHE_SIZE {
+        histograms.remove(0);
+    }
+    histograms.push(entry);
+    &histograms.last().unwrap().1
+}
+
+// Threshold controls for a single mask rule, returns true when something was changed
+fn mask_rule_ui(
+    ui: &mut egui::Ui,
+    id: impl std::hash::Hash,
+    rule: &mut MaskRule,
+    histogram: Option<&[u32]>,
+    (auto_threshold, auto_coverage): &(AutoThresholdChoice, f64),
+    reference_color: [u8; 3],
+) -> bool {
+    let mut changed = false;
+    ui.horizontal(|ui| {
+        let funcs = mask_funcs(reference_color);
+        let selected = funcs.iter().find(|(choice, _)| same_mask_func(choice, &rule.mask_func_choice)).map_or("", |(_, name)| name);
+        egui::ComboBox::new(id, "Mask function")
+            .selected_text(selected)
+            .show_ui(ui, |ui| {
+                for (choice, name) in funcs {
+                    let is_selected = same_mask_func(&choice, &rule.mask_func_choice);
+                    // Reselecting keeps the bucket or level count of the current one
+                    if ui.selectable_label(is_selected, name).clicked() && !is_selected {
+                        rule.mask_func_choice = choice;
+                        changed = true;
+                    }
+                }
+            });
+        if let MaskFuncChoice::Color(value) = &mut rule.mask_func_choice {
+            changed |= color_value_steps_ui(ui, value);
+        }
+        ui.add_space(20.0);
+        changed |= ui.checkbox(&mut rule.invert_mask, "Invert mask?").changed();
+        if rule.mask_func_choice.is_neighbourhood() {
+            changed |= ui.checkbox(&mut rule.edge_hysteresis, "Hysteresis").changed();
+        }
+    });
+    if let Some(histogram) = histogram {
+        histogram_ui(ui, histogram, rule);
+    }
+    ui.horizontal(|ui| {
+        let (mask_range_from, mask_range_to) = rule.mask_func_choice.get_range();
+
+        let lt_slider = ui.add(egui::Slider::new(&mut rule.low_threshold, mask_range_from..=mask_range_to).text("Low threshold"));
+        changed |= ui.checkbox(&mut rule.include_low, "inclusive").changed();
+        ui.add_space(5.0);
+        let ht_slider = ui.add(egui::Slider::new(&mut rule.high_threshold, mask_range_from..=mask_range_to).text("High threshold"));
+        changed |= ui.checkbox(&mut rule.include_high, "inclusive").changed();
+        changed |= lt_slider.changed() || ht_slider.changed();
+        if let Some(histogram) = histogram {
+            ui.add_space(5.0);
+            if ui.button("Auto").clicked() {
+                histogram::auto_threshold(rule, histogram, auto_threshold, *auto_coverage);
+                changed = true;
+            }
+        }
+    });
+    changed
+}
+
+// Histogram of the mask function with the masked part of its range highlighted
+fn histogram_ui(ui: &mut egui::Ui, histogram: &[u32], rule: &MaskRule) {
+    let (rect, _) = ui.allocate_exact_size(vec2(ui.available_width().min(400.0), 48.0), Sense::hover());
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(rect, 2.0, ui.visuals().extreme_bg_color);
+
+    let (mask_range_from, mask_range_to) = rule.mask_func_choice.get_range();
+    let x = |v: f64| rect.left() + ((v - mask_range_from) / (mask_range_to - mask_range_from)).clamp(0.0, 1.0) as f32 * rect.width();
+    let (low, high) = (x(rule.low_threshold), x(rule.high_threshold));
+    let highlight = Color32::from_rgba_unmultiplied(90, 150, 255, 60);
+    // A hue range with low above high wraps around, so it's the outside part that's masked
+    let wraps = rule.mask_func_choice.period().is_some() && low > high;
+    let masked = if rule.invert_mask ^ wraps {
+        vec![(rect.left(), low.min(high)), (high.max(low), rect.right())]
+    } else {
+        vec![(low.min(high), low.max(high))]
+    };
+    for (from, to) in masked.into_iter().filter(|(from, to)| from < to) {
+        painter.rect_filled(Rect::from_x_y_ranges(from..=to, rect.y_range()), 0.0, highlight);
+    }
+
+    // Square root scale so that small peaks stay visible next to a large one
+    let max = histogram.iter().max().copied().unwrap_or(0).max(1) as f32;
+    let bar_width = rect.width() / histogram.len() as f32;
+    for (i, count) in histogram.iter().enumerate().filter(|(_, count)| **count > 0) {
+        let bar_height = (*count as f32 / max).sqrt() * rect.height();
+        let left = rect.left() + i as f32 * bar_width;
+        painter.rect_filled(
+            Rect::from_min_max(pos2(left, rect.bottom() - bar_height), pos2(left + bar_width, rect.bottom())),
+            0.0,
+            ui.visuals().text_color(),
+        );
+    }
+
+    for threshold in [low, high] {
+        painter.line_segment([pos2(threshold, rect.top()), pos2(threshold, rect.bottom())], Stroke::new(1.0, Color32::LIGHT_BLUE));
+    }
+}
+
+// Full resolution mask for the effect and the export. Masks made elsewhere still need the transparent
+// pixels taken out, generated ones already had that done.
+fn full_mask(image: &RgbaImage, loaded_mask: Option<GrayImage>, mask_override: Option<&MaskOverride>, params: &MaskParams) -> GrayImage {
+    let edited = loaded_mask.is_some() || mask_override.is_some();
+    let mut mask = loaded_mask.unwrap_or_else(|| pixel_sorting_rust::gen_mask(image, params));
+    if let Some(mask_override) = mask_override {
+        mask_override.apply(&mut mask);
+    }
+    if edited {
+        pixel_sorting_rust::finish_mask(image, &mut mask, params);
+    }
+    mask
+}
+
+fn load_texture_from_dynamic_image(image: &DynamicImage, ctx: &egui::Context) -> TextureHandle {
+    let image = image.to_rgba8();
+    let (width, height) = image.dimensions();
+
+    let color_image = ColorImage::from_rgba_unmultiplied([width as usize, height as usize], &image);
+    ctx.load_texture("my_image", color_image, Default::default())
+}
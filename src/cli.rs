@@ -0,0 +1,198 @@
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Instant;
+use image::DynamicImage;
+
+use pixel_sorting_rust::{EffectParams, histogram, mask, palette, preset};
+use pixel_sorting_rust::histogram::AutoThresholdChoice;
+use pixel_sorting_rust::pixel_generators::PixelAddChoice;
+
+pub const USAGE: &str = "Usage: pixel-sorting-rust sort --input <path> --output <path> [options]
+
+Options:
+  --preset <path>          Start from a preset saved in the GUI, later options override it
+  --mask <path>            Use a grayscale mask image instead of the thresholds below
+  --low <value>            Low mask threshold (default: 195)
+  --high <value>           High mask threshold (default: 255)
+  --invert                 Invert the mask
+  --include-low            Values equal to --low are masked too
+  --include-high           Values equal to --high are masked too
+                           With hue functions --low above --high wraps around 360, e.g. --low 340 --high 20
+  --auto-threshold <name>  Pick --low and --high from the image: otsu, coverage
+  --coverage <value>       Share of pixels to mask with `--auto-threshold coverage`, 0..1 (default: 0.3)
+  --edge-hysteresis        With edge functions keep values above --low only when connected to values above --high
+  --mask-layer <layer>     Combine another rule into the mask, can be repeated.
+                           Format: operator:function:low:high[:invert][:include_low][:include_high]
+                           with operator one of and, or, xor, subtract, min, max. Example: and:hue:180:260
+  --feather <value>        Soft mask edge width in mask function units, 0 = hard mask (default: 0)
+  --morphology <step>      Clean up the mask: dilate, erode, open or close, with an optional radius in pixels
+                           (default: 1), e.g. close:3. Can be repeated, the steps run in order
+  --min-component <pixels> Drop masked areas smaller than this, 0 = keep all (default: 0)
+  --mask-blur <sigma>      Gaussian blur applied to the mask, 0 = off (default: 0)
+  --sort-transparent       Also sort fully transparent pixels, they're left in place by default
+  --mask-func <name>       luminance, posterized_luminance=<levels>, hue, quantized_hue=<buckets>, red, green, blue,
+                           color_sum, alpha, hsl_saturation, hsv_saturation, hsv_value, lab_l, lab_a, lab_b, oklab_l,
+                           oklch_chroma, oklch_hue, distance_to=#rrggbb, sobel_edges, scharr_edges (default: luminance)
+  --sort-key <name>        hue, quantized_hue=<buckets>, luminance, posterized_luminance=<levels>, red, green, blue,
+                           color_sum, alpha, hsl_saturation, hsv_saturation, hsv_value, lab_l, lab_a, lab_b, oklab_l,
+                           oklch_chroma, oklch_hue, distance_to=#rrggbb (default: hue)
+  --tiebreak-key <name>    Sort key for pixels with an equal --sort-key, same names as --sort-key (default: none)
+  --pixel-add <name>       random_pixel, random_red_shade, random_blue_shade, random_green_shade, black,
+                           palette=#rrggbb[*weight]:#rrggbb[*weight]..., linear_gradient[=#start:#end:angle],
+                           radial_gradient[=#center:#edge], perlin_noise[=scale], jitter[=amount], inverted,
+                           channel_rotated (default: random_pixel)
+  --palette <path>         Add random colors from a GIMP .gpl palette or a file with one #rrggbb color per line
+  --palette-from-image <k> Add random colors from the k main colors of the input image
+  --palette-weighted       Pick palette colors as often as they appear in the input image
+  --keep-alpha             Added pixels keep the alpha of the pixels they replace
+  --prob <value>           Pixel addition probability, 0..1 (default: 0.45)
+  --mode <name>            interval, whole_row (default: interval)
+  --direction <name>       horizontal, vertical, diagonal_down, diagonal_up, angle (default: horizontal)
+  --angle <degrees>        Angle used with `--direction angle` (default: 30)
+  --order <name>           ascending, descending, random_per_span (default: ascending)
+  --seed <value>           Seed for everything random, same seed gives the same image (default: 0)
+  --max-span <length>      Break spans longer than this, 0 = unlimited (default: 0)
+  --min-span <length>      Shortest chunk with `--random-span` (default: 1)
+  --random-span            Pick chunk lengths randomly from min-span..=max-span
+  --quiet                  Do not print timings";
+
+pub struct CliArgs {
+    input: PathBuf,
+    output: PathBuf,
+    mask: Option<PathBuf>,
+    params: EffectParams,
+    auto_threshold: Option<AutoThresholdChoice>,
+    coverage: f64,
+    palette_size: Option<usize>,
+    palette_weighted: bool,
+    quiet: bool,
+}
+
+fn parse_value<T: FromStr<Err=E>, E: Display>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or(format!("Missing value for {}", flag))?;
+    value.parse::<T>().map_err(|e| format!("Invalid value for {}: {}", flag, e))
+}
+
+pub fn parse_args<I: Iterator<Item=String>>(mut args: I) -> Result<CliArgs, String> {
+    let mut input: Option<PathBuf> = None;
+    let mut output: Option<PathBuf> = None;
+    let mut cli_args = CliArgs {
+        input: PathBuf::new(),
+        output: PathBuf::new(),
+        mask: None,
+        params: EffectParams::default(),
+        auto_threshold: None,
+        coverage: 0.3,
+        palette_size: None,
+        palette_weighted: false,
+        quiet: false,
+    };
+
+    while let Some(flag) = args.next() {
+        match flag.as_str() {
+            "--input" | "-i" => { input = Some(parse_value(&flag, args.next())?) }
+            "--output" | "-o" => { output = Some(parse_value(&flag, args.next())?) }
+            "--preset" => {
+                let path: PathBuf = parse_value(&flag, args.next())?;
+                cli_args.params = preset::load_preset(&path)?
+            }
+            "--mask" => { cli_args.mask = Some(parse_value(&flag, args.next())?) }
+            "--low" => { cli_args.params.mask.mask_rule.low_threshold = parse_value(&flag, args.next())? }
+            "--high" => { cli_args.params.mask.mask_rule.high_threshold = parse_value(&flag, args.next())? }
+            "--invert" => { cli_args.params.mask.mask_rule.invert_mask = true }
+            "--include-low" => { cli_args.params.mask.mask_rule.include_low = true }
+            "--include-high" => { cli_args.params.mask.mask_rule.include_high = true }
+            "--auto-threshold" => { cli_args.auto_threshold = Some(parse_value(&flag, args.next())?) }
+            "--coverage" => { cli_args.coverage = parse_value(&flag, args.next())? }
+            "--edge-hysteresis" => { cli_args.params.mask.mask_rule.edge_hysteresis = true }
+            "--mask-layer" => { cli_args.params.mask.mask_layers.push(parse_value(&flag, args.next())?) }
+            "--feather" => { cli_args.params.mask.mask_feather = parse_value(&flag, args.next())? }
+            "--morphology" => { cli_args.params.mask.mask_morphology.push(parse_value(&flag, args.next())?) }
+            "--min-component" => { cli_args.params.mask.mask_min_component = parse_value(&flag, args.next())? }
+            "--mask-blur" => { cli_args.params.mask.mask_blur = parse_value(&flag, args.next())? }
+            "--sort-transparent" => { cli_args.params.mask.mask_exclude_transparent = false }
+            "--keep-alpha" => { cli_args.params.pixel_add_keep_alpha = true }
+            "--mask-func" => { cli_args.params.mask.mask_rule.mask_func_choice = parse_value(&flag, args.next())? }
+            "--sort-key" => { cli_args.params.pixel_sort_choice = parse_value(&flag, args.next())? }
+            "--tiebreak-key" => { cli_args.params.pixel_sort_tiebreak = Some(parse_value(&flag, args.next())?) }
+            "--pixel-add" => { cli_args.params.pixel_add_choice = parse_value(&flag, args.next())? }
+            "--palette" => {
+                let path: PathBuf = parse_value(&flag, args.next())?;
+                cli_args.params.pixel_add_choice = PixelAddChoice::Palette { colors: palette::load_palette(&path)?, weights: vec![] }
+            }
+            "--palette-from-image" => { cli_args.palette_size = Some(parse_value(&flag, args.next())?) }
+            "--palette-weighted" => { cli_args.palette_weighted = true }
+            "--prob" => { cli_args.params.random_prob = parse_value(&flag, args.next())? }
+            "--mode" => { cli_args.params.sort_options.mode = parse_value(&flag, args.next())? }
+            "--direction" => { cli_args.params.sort_options.direction = parse_value(&flag, args.next())? }
+            "--angle" => { cli_args.params.sort_options.angle = parse_value(&flag, args.next())? }
+            "--order" => { cli_args.params.sort_options.order = parse_value(&flag, args.next())? }
+            "--seed" => { cli_args.params.seed = parse_value(&flag, args.next())? }
+            "--max-span" => { cli_args.params.sort_options.max_span_length = parse_value(&flag, args.next())? }
+            "--min-span" => { cli_args.params.sort_options.min_span_length = parse_value(&flag, args.next())? }
+            "--random-span" => { cli_args.params.sort_options.random_span_length = true }
+            "--quiet" | "-q" => { cli_args.quiet = true }
+            _ => { return Err(format!("Unknown argument: {}", flag)) }
+        }
+    }
+
+    if !(0.0..=1.0).contains(&cli_args.params.random_prob) {
+        return Err(format!("Pixel addition probability must be between 0 and 1, got {}", cli_args.params.random_prob));
+    }
+    if cli_args.palette_size == Some(0) {
+        return Err("Palette needs at least one color".to_string());
+    }
+    // A palette can also come from a preset
+    if cli_args.palette_weighted && cli_args.palette_size.is_none() && !matches!(cli_args.params.pixel_add_choice, PixelAddChoice::Palette { .. }) {
+        return Err("--palette-weighted needs --palette, --palette-from-image or a preset with a palette".to_string());
+    }
+    if !(0.0..=1.0).contains(&cli_args.coverage) {
+        return Err(format!("Coverage must be between 0 and 1, got {}", cli_args.coverage));
+    }
+    cli_args.input = input.ok_or("Missing --input")?;
+    cli_args.output = output.ok_or("Missing --output")?;
+    Ok(cli_args)
+}
+
+pub fn run(mut args: CliArgs) -> Result<(), String> {
+    let start = Instant::now();
+    let image = image::open(&args.input).map_err(|e| format!("Failed to open {}: {}", args.input.display(), e))?.to_rgba8();
+
+    if let Some(auto_threshold_choice) = &args.auto_threshold {
+        let rule = &mut args.params.mask.mask_rule;
+        let histogram = histogram::histogram(&image, &rule.mask_func_choice);
+        histogram::auto_threshold(rule, &histogram, auto_threshold_choice, args.coverage);
+        if !args.quiet {
+            println!("Thresholds: {:.1}..{:.1}", rule.low_threshold, rule.high_threshold);
+        }
+    }
+
+    if let Some(k) = args.palette_size {
+        let (colors, weights) = palette::extract_palette(&image, k, args.params.seed);
+        args.params.pixel_add_choice = PixelAddChoice::Palette { colors, weights: if args.palette_weighted { weights } else { vec![] } };
+    } else if let PixelAddChoice::Palette { colors, weights } = &mut args.params.pixel_add_choice {
+        if args.palette_weighted {
+            *weights = palette::color_frequencies(&image, colors);
+        }
+    }
+
+    let result = match &args.mask {
+        Some(path) => {
+            let mask = mask::load_mask(path, image.width(), image.height())?;
+            pixel_sorting_rust::apply_with_mask(&image, &mask, &args.params)
+        }
+        None => { pixel_sorting_rust::apply(&image, &args.params) }
+    };
+
+    // JPEG has no alpha channel
+    let result = match args.output.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase()).as_deref() {
+        Some("jpg") | Some("jpeg") => { DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(result).to_rgb8()) }
+        _ => { DynamicImage::ImageRgba8(result) }
+    };
+    result.save(&args.output).map_err(|e| format!("Failed to save {}: {}", args.output.display(), e))?;
+    if !args.quiet {
+        println!("{} -> {} in {:?}", args.input.display(), args.output.display(), start.elapsed());
+    }
+    Ok(())
+}
@@ -0,0 +1,128 @@
+use std::collections::VecDeque;
+use std::f32::consts::SQRT_2;
+use image::{ImageBuffer, Luma, Rgba};
+use rayon::prelude::*;
+
+use crate::pixel::luminance;
+
+pub enum EdgeOperator {
+    Sobel,
+    Scharr,
+}
+
+impl EdgeOperator {
+    // Weights of the smoothing part of the 3x3 kernel, the derivative part is always [-1, 0, 1]
+    fn smoothing(&self) -> [f32; 3] {
+        match self {
+            EdgeOperator::Sobel => { [1.0, 2.0, 1.0] }
+            EdgeOperator::Scharr => { [3.0, 10.0, 3.0] }
+        }
+    }
+}
+
+// Gradient magnitude of the luminance divided by the kernel weight sum and √2, so a full contrast
+// horizontal or vertical step is 255 / √2 ≈ 180 and no edge goes above 255 (at most ≈ 202 for Sobel
+// and ≈ 213 for Scharr, with corners)
+pub fn gradient_magnitude(image: &ImageBuffer::<Rgba<u8>, Vec<u8>>, operator: &EdgeOperator) -> ImageBuffer::<Luma<f32>, Vec<f32>> {
+    let (width, height) = image.dimensions();
+    let lum: Vec<f32> = image.pixels().map(|p| luminance(p) as f32).collect();
+    let smoothing = operator.smoothing();
+    let scale = 1.0 / (smoothing.iter().sum::<f32>() * SQRT_2);
+
+    // Borders repeat the outermost pixels
+    let at = |x: i64, y: i64| -> f32 {
+        let x = x.clamp(0, width as i64 - 1) as u32;
+        let y = y.clamp(0, height as i64 - 1) as u32;
+        lum[(y * width + x) as usize]
+    };
+
+    let values: Vec<f32> = (0..height).into_par_iter().flat_map(|y| {
+        (0..width).map(|x| {
+            let (x, y) = (x as i64, y as i64);
+            let (mut gx, mut gy) = (0.0, 0.0);
+            for (i, w) in smoothing.iter().enumerate() {
+                let o = i as i64 - 1;
+                gx += w * (at(x + 1, y + o) - at(x - 1, y + o));
+                gy += w * (at(x + o, y + 1) - at(x + o, y - 1));
+            }
+            (gx * gx + gy * gy).sqrt() * scale
+        }).collect::<Vec<f32>>()
+    }).collect();
+
+    ImageBuffer::<Luma<f32>, Vec<f32>>::from_vec(width, height, values).unwrap()
+}
+
+// Canny-style hysteresis: values at or above `high` are edges, values above `low` are edges
+// only if they are connected to a strong edge
+pub fn hysteresis(values: &ImageBuffer::<Luma<f32>, Vec<f32>>, low: f64, high: f64) -> Vec<bool> {
+    let (width, height) = values.dimensions();
+    let (low, high) = (low as f32, high as f32);
+    let mut edges: Vec<bool> = values.pixels().map(|v| v.0[0] >= high).collect();
+    let mut queue: VecDeque<(u32, u32)> = (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .filter(|(x, y)| edges[(y * width + x) as usize])
+        .collect();
+
+    while let Some((x, y)) = queue.pop_front() {
+        for ny in y.saturating_sub(1)..=(y + 1).min(height - 1) {
+            for nx in x.saturating_sub(1)..=(x + 1).min(width - 1) {
+                let i = (ny * width + nx) as usize;
+                if !edges[i] && values.get_pixel(nx, ny).0[0] > low {
+                    edges[i] = true;
+                    queue.push_back((nx, ny));
+                }
+            }
+        }
+    }
+    edges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(width: u32, pixels: Vec<f32>) -> ImageBuffer::<Luma<f32>, Vec<f32>> {
+        let height = pixels.len() as u32 / width;
+        ImageBuffer::<Luma<f32>, Vec<f32>>::from_vec(width, height, pixels).unwrap()
+    }
+
+    #[test]
+    fn step_magnitude() {
+        // Black left half, white right half
+        let image = ImageBuffer::<Rgba<u8>, Vec<u8>>::from_fn(6, 4, |x, _| if x < 3 { Rgba([0, 0, 0, 255]) } else { Rgba([255, 255, 255, 255]) });
+        for operator in [EdgeOperator::Sobel, EdgeOperator::Scharr] {
+            let magnitude = gradient_magnitude(&image, &operator);
+            for y in 0..4 {
+                let row: Vec<f32> = (0..6).map(|x| magnitude.get_pixel(x, y).0[0]).collect();
+                let step = 255.0 / SQRT_2;
+                assert!(row.iter().zip([0.0, 0.0, step, step, 0.0, 0.0]).all(|(v, e)| (v - e).abs() < 0.01), "{:?}", row);
+            }
+        }
+    }
+
+    #[test]
+    fn strongest_edge_stays_below_255() {
+        // Every 3x3 neighbourhood of this checkerboard of 2x2 blocks holds a corner
+        let image = ImageBuffer::<Rgba<u8>, Vec<u8>>::from_fn(8, 8, |x, y| if (x / 2 + y / 2) % 2 == 0 { Rgba([0, 0, 0, 255]) } else { Rgba([255, 255, 255, 255]) });
+        for operator in [EdgeOperator::Sobel, EdgeOperator::Scharr] {
+            assert!(gradient_magnitude(&image, &operator).pixels().all(|v| v.0[0] <= 255.0));
+        }
+    }
+
+    #[test]
+    fn hysteresis_keeps_weak_edges_connected_to_strong_ones() {
+        let edges = hysteresis(&values(6, vec![
+            90.0, 40.0, 0.0, 0.0, 40.0, 40.0,
+            0.0, 0.0, 40.0, 0.0, 0.0, 0.0,
+            0.0, 0.0, 0.0, 0.0, 10.0, 90.0,
+        ]), 20.0, 80.0);
+        assert_eq!(edges, vec![
+            true, true, false, false, false, false,
+            false, false, true, false, false, false,
+            false, false, false, false, false, true,
+        ]);
+
+        // Values equal to the low threshold are not edges, values equal to the high one are
+        assert_eq!(hysteresis(&values(3, vec![80.0, 20.0, 20.0]), 20.0, 80.0), vec![true, false, false]);
+    }
+}
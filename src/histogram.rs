@@ -0,0 +1,173 @@
+use std::str::FromStr;
+use image::{ImageBuffer, Luma, Rgba};
+use serde::{Deserialize, Serialize};
+
+use crate::mask::{MaskFuncChoice, MaskRule};
+
+pub const HISTOGRAM_BINS: usize = 256;
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AutoThresholdChoice {
+    // Otsu's method, splits the values into two classes as different as possible
+    Otsu,
+    // Masks a given share of the pixels
+    Coverage,
+}
+
+impl FromStr for AutoThresholdChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "otsu" => { Ok(AutoThresholdChoice::Otsu) }
+            "coverage" => { Ok(AutoThresholdChoice::Coverage) }
+            _ => { Err(format!("Unknown auto threshold method: {}", s)) }
+        }
+    }
+}
+
+// Pixel counts of the mask function values split into `HISTOGRAM_BINS` equal steps of its range
+pub fn histogram(image: &ImageBuffer::<Rgba<u8>, Vec<u8>>, mask_func_choice: &MaskFuncChoice) -> Vec<u32> {
+    let (from, to) = mask_func_choice.get_range();
+    let mut bins = vec![0; HISTOGRAM_BINS];
+    for v in mask_func_choice.get_values(image).as_raw() {
+        let bin = ((*v as f64 - from) / (to - from) * HISTOGRAM_BINS as f64) as usize;
+        bins[bin.min(HISTOGRAM_BINS - 1)] += 1;
+    }
+    bins
+}
+
+// Mask function value where `bin` starts
+pub fn bin_value(mask_func_choice: &MaskFuncChoice, bin: usize) -> f64 {
+    let (from, to) = mask_func_choice.get_range();
+    from + (to - from) * bin as f64 / HISTOGRAM_BINS as f64
+}
+
+// First bin of the upper class that maximises the variance between the two classes
+pub fn otsu_bin(histogram: &[u32]) -> usize {
+    let total: f64 = histogram.iter().map(|c| *c as f64).sum();
+    let total_sum: f64 = histogram.iter().enumerate().map(|(i, c)| i as f64 * *c as f64).sum();
+
+    let (mut best_bin, mut best_variance) = (0, -1.0);
+    let (mut lower_count, mut lower_sum) = (0.0, 0.0);
+    for (i, c) in histogram.iter().enumerate() {
+        if lower_count > 0.0 && lower_count < total {
+            let upper_count = total - lower_count;
+            let mean_difference = lower_sum / lower_count - (total_sum - lower_sum) / upper_count;
+            let variance = lower_count * upper_count * mean_difference * mean_difference;
+            if variance > best_variance {
+                best_variance = variance;
+                best_bin = i;
+            }
+        }
+        lower_count += *c as f64;
+        lower_sum += i as f64 * *c as f64;
+    }
+    best_bin
+}
+
+// Highest bin such that this bin and the ones above hold at least `coverage` of the pixels,
+// `histogram.len()` when nothing has to be covered
+pub fn coverage_bin(histogram: &[u32], coverage: f64) -> usize {
+    let target = histogram.iter().map(|c| *c as f64).sum::<f64>() * coverage.clamp(0.0, 1.0);
+    if target <= 0.0 {
+        return histogram.len();
+    }
+    let mut count = 0.0;
+    for (i, c) in histogram.iter().enumerate().rev() {
+        count += *c as f64;
+        if count >= target {
+            return i;
+        }
+    }
+    0
+}
+
+// Sets the thresholds of `rule` to mask the upper part of `histogram`, `coverage` is only used by
+// `AutoThresholdChoice::Coverage`
+pub fn auto_threshold(rule: &mut MaskRule, histogram: &[u32], auto_threshold_choice: &AutoThresholdChoice, coverage: f64) {
+    let bin = match auto_threshold_choice {
+        AutoThresholdChoice::Otsu => { otsu_bin(histogram) }
+        // An inverted mask keeps what's outside the thresholds
+        AutoThresholdChoice::Coverage => { coverage_bin(histogram, if rule.invert_mask { 1.0 - coverage } else { coverage }) }
+    };
+    rule.low_threshold = bin_value(&rule.mask_func_choice, bin);
+    rule.high_threshold = rule.mask_func_choice.get_range().1;
+    // Values from the start of `bin` up, past the last bin the range is empty
+    rule.include_low = bin < histogram.len();
+    rule.include_high = true;
+}
+
+// Share of the image that gets sorted, soft mask pixels count partially
+pub fn mask_coverage(mask: &ImageBuffer::<Luma<u8>, Vec<u8>>) -> f64 {
+    let sum: u64 = mask.as_raw().iter().map(|v| *v as u64).sum();
+    sum as f64 / (mask.as_raw().len().max(1) as f64 * 255.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn histogram_with(bins: &[(usize, u32)]) -> Vec<u32> {
+        let mut histogram = vec![0; HISTOGRAM_BINS];
+        for (bin, count) in bins {
+            histogram[*bin] = *count;
+        }
+        histogram
+    }
+
+    #[test]
+    fn otsu_splits_two_peaks() {
+        let bin = otsu_bin(&histogram_with(&[(10, 500), (200, 300)]));
+        assert!((11..=200).contains(&bin), "{}", bin);
+        let bin = otsu_bin(&histogram_with(&[(20, 100), (30, 100), (150, 100), (160, 100)]));
+        assert!((31..=150).contains(&bin), "{}", bin);
+    }
+
+    #[test]
+    fn otsu_without_two_classes() {
+        assert_eq!(otsu_bin(&histogram_with(&[])), 0);
+        assert_eq!(otsu_bin(&histogram_with(&[(77, 1000)])), 0);
+        assert_eq!(otsu_bin(&[]), 0);
+    }
+
+    #[test]
+    fn coverage_bin_edges() {
+        let histogram = histogram_with(&[(0, 25), (100, 50), (255, 25)]);
+        assert_eq!(coverage_bin(&histogram, 0.0), HISTOGRAM_BINS);
+        assert_eq!(coverage_bin(&histogram, -1.0), HISTOGRAM_BINS);
+        assert_eq!(coverage_bin(&histogram, 0.25), 255);
+        assert_eq!(coverage_bin(&histogram, 0.5), 100);
+        assert_eq!(coverage_bin(&histogram, 0.75), 100);
+        assert_eq!(coverage_bin(&histogram, 0.9), 0);
+        assert_eq!(coverage_bin(&histogram, 1.0), 0);
+        assert_eq!(coverage_bin(&histogram, 2.0), 0);
+        assert_eq!(coverage_bin(&histogram_with(&[]), 0.5), HISTOGRAM_BINS);
+    }
+
+    #[test]
+    fn auto_threshold_masks_the_requested_share() {
+        let image = ImageBuffer::<Rgba<u8>, Vec<u8>>::from_fn(16, 16, |x, y| {
+            let v = (y * 16 + x) as u8;
+            Rgba([v, v, v, 255])
+        });
+        let histogram = histogram(&image, &MaskRule::default().mask_func_choice);
+        for include_low in [false, true] {
+            for coverage in [0.0, 0.25, 0.5, 1.0] {
+                let mut rule = MaskRule { include_low, ..Default::default() };
+                auto_threshold(&mut rule, &histogram, &AutoThresholdChoice::Coverage, coverage);
+                assert!((mask_coverage(&rule.mask_image(&image, 0.0)) - coverage).abs() < 0.01, "{} {}", include_low, coverage);
+            }
+            let mut rule = MaskRule { include_low, ..Default::default() };
+            auto_threshold(&mut rule, &histogram, &AutoThresholdChoice::Coverage, 0.0);
+            assert_eq!(mask_coverage(&rule.mask_image(&image, 0.0)), 0.0);
+            rule.invert_mask = true;
+            auto_threshold(&mut rule, &histogram, &AutoThresholdChoice::Coverage, 0.0);
+            assert_eq!(mask_coverage(&rule.mask_image(&image, 0.0)), 0.0);
+        }
+    }
+}
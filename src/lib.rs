@@ -0,0 +1,221 @@
+use image::{GrayImage, RgbaImage};
+use image::imageops::blur;
+use serde::{Deserialize, Serialize};
+
+use crate::mask::{MaskLayer, MaskRule};
+use crate::morphology::MorphologyStep;
+use crate::pixel::PixelSortKeyChoice;
+use crate::pixel_generators::PixelAddChoice;
+use crate::sort_effect::{process_sorting_effect, Progress, SortKey, SortOptions};
+
+pub mod sort_effect;
+pub mod pixel_generators;
+pub mod pixel;
+pub mod mask;
+pub mod edges;
+pub mod histogram;
+pub mod morphology;
+pub mod preset;
+pub mod palette;
+
+/// Everything that decides which pixels get sorted.
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct MaskParams {
+    #[serde(flatten)]
+    pub mask_rule: MaskRule,
+    // Extra rules applied on top of `mask_rule` in order
+    pub mask_layers: Vec<MaskLayer>,
+    // Cleanup of the combined mask, applied in order
+    pub mask_morphology: Vec<MorphologyStep>,
+    // Masked areas smaller than this many pixels are dropped, 0 keeps everything
+    pub mask_min_component: u32,
+    // Width of the soft edge in mask function units, 0 gives a hard mask
+    pub mask_feather: f64,
+    // Gaussian blur sigma applied to the mask, 0 disables it
+    pub mask_blur: f32,
+    // Fully transparent pixels are never sorted, so they can't move into visible areas
+    pub mask_exclude_transparent: bool,
+}
+
+impl Default for MaskParams {
+    fn default() -> Self {
+        Self {
+            mask_rule: MaskRule::default(),
+            mask_layers: vec![],
+            mask_morphology: vec![],
+            mask_min_component: 0,
+            mask_feather: 0.0,
+            mask_blur: 0.0,
+            mask_exclude_transparent: true,
+        }
+    }
+}
+
+/// Everything that controls how an image is masked and sorted.
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct EffectParams {
+    #[serde(flatten)]
+    pub mask: MaskParams,
+    pub random_prob: f64,
+    pub pixel_add_choice: PixelAddChoice,
+    // Added pixels take the alpha of the pixel they replace instead of being opaque
+    pub pixel_add_keep_alpha: bool,
+    pub pixel_sort_choice: PixelSortKeyChoice,
+    // Orders pixels with an equal `pixel_sort_choice` key
+    pub pixel_sort_tiebreak: Option<PixelSortKeyChoice>,
+    pub sort_options: SortOptions,
+    // Same seed and settings always give the same image
+    pub seed: u64,
+}
+
+impl Default for EffectParams {
+    fn default() -> Self {
+        Self {
+            mask: MaskParams::default(),
+            random_prob: 0.45,
+            pixel_add_choice: PixelAddChoice::RandomPixel,
+            pixel_add_keep_alpha: false,
+            pixel_sort_choice: PixelSortKeyChoice::Hue,
+            pixel_sort_tiebreak: None,
+            sort_options: SortOptions::default(),
+            seed: 0,
+        }
+    }
+}
+
+/// Builds the mask selecting which pixels get sorted (255) and which stay in place (0).
+/// Values in between blend the sorted pixel with the original one.
+pub fn gen_mask(image: &RgbaImage, params: &MaskParams) -> GrayImage {
+    let mut mask = params.mask_rule.mask_image(image, params.mask_feather);
+    for layer in &params.mask_layers {
+        mask::combine_masks(&mut mask, &layer.rule.mask_image(image, params.mask_feather), &layer.combine);
+    }
+    let mut mask = morphology::apply_steps(&mask, &params.mask_morphology);
+    morphology::remove_small_components(&mut mask, params.mask_min_component);
+    let mut mask = if params.mask_blur > 0.0 { blur(&mask, params.mask_blur) } else { mask };
+    finish_mask(image, &mut mask, params);
+    mask
+}
+
+/// Applies the settings that every mask has to follow, also to masks loaded from disk or painted over.
+pub fn finish_mask(image: &RgbaImage, mask: &mut GrayImage, params: &MaskParams) {
+    if params.mask_exclude_transparent {
+        mask::exclude_transparent(mask, image);
+    }
+}
+
+/// Sorts the pixels of `image` selected by a mask made elsewhere, e.g. loaded from disk.
+pub fn apply_with_mask(image: &RgbaImage, mask: &GrayImage, params: &EffectParams) -> RgbaImage {
+    let mut mask = mask.clone();
+    finish_mask(image, &mut mask, &params.mask);
+    apply_with_progress(image, &mask, params, &Progress::default()).unwrap()
+}
+
+/// Sorts with a mask from [`gen_mask`] or passed through [`finish_mask`], reports progress and
+/// returns `None` once `progress` is cancelled.
+pub fn apply_with_progress(image: &RgbaImage, mask: &GrayImage, params: &EffectParams, progress: &Progress) -> Option<RgbaImage> {
+    process_sorting_effect(
+        image, mask, params.random_prob,
+        |x, y, p, rng| {
+            let mut added = params.pixel_add_choice.get_pixel(x, y, p, image.dimensions(), rng);
+            if params.pixel_add_keep_alpha {
+                added.0[3] = p.0[3];
+            }
+            added
+        },
+        |p| (
+            SortKey(params.pixel_sort_choice.get_value(p)),
+            SortKey(params.pixel_sort_tiebreak.as_ref().map_or(0.0, |key| key.get_value(p))),
+        ),
+        &params.sort_options,
+        params.seed,
+        progress,
+    )
+}
+
+/// Applies the whole effect: masks the image and sorts the masked pixels.
+pub fn apply(image: &RgbaImage, params: &EffectParams) -> RgbaImage {
+    apply_with_progress(image, &gen_mask(image, &params.mask), params, &Progress::default()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_image() -> RgbaImage {
+        RgbaImage::from_fn(40, 30, |x, y| image::Rgba([(x * 6) as u8, (y * 8) as u8, ((x * y) % 256) as u8, 255]))
+    }
+
+    #[test]
+    fn same_seed_gives_same_image() {
+        let image = test_image();
+        let mut params = EffectParams { random_prob: 0.5, ..EffectParams::default() };
+        params.mask.mask_rule.low_threshold = 0.0;
+        params.sort_options.max_span_length = 8;
+        params.sort_options.random_span_length = true;
+        params.seed = 42;
+        assert!(apply(&image, &params) == apply(&image, &params));
+
+        let other_seed = EffectParams { seed: 43, ..params.clone() };
+        assert!(apply(&image, &params) != apply(&image, &other_seed));
+    }
+
+    // Every pixel is masked and half of them get replaced by added pixels
+    fn sort_everything() -> EffectParams {
+        let mut params = EffectParams { random_prob: 0.5, pixel_add_choice: PixelAddChoice::Black, ..EffectParams::default() };
+        params.mask.mask_rule.low_threshold = 0.0;
+        params.mask.mask_rule.include_low = true;
+        params.mask.mask_rule.include_high = true;
+        params.sort_options.mode = sort_effect::SortModeChoice::WholeRow;
+        params
+    }
+
+    #[test]
+    fn transparent_pixels_stay_in_place() {
+        let mut image = test_image();
+        for (x, y, p) in image.enumerate_pixels_mut() {
+            if (x + y) % 3 == 0 {
+                *p = image::Rgba([x as u8, y as u8, 7, 0]);
+            }
+        }
+        let params = sort_everything();
+        let full_mask = GrayImage::from_pixel(image.width(), image.height(), image::Luma([255]));
+        for result in [apply(&image, &params), apply_with_mask(&image, &full_mask, &params)] {
+            for (x, y, p) in image.enumerate_pixels() {
+                if p.0[3] == 0 {
+                    assert_eq!(result.get_pixel(x, y), p);
+                } else {
+                    assert_ne!(result.get_pixel(x, y).0[3], 0);
+                }
+            }
+        }
+
+        let sort_transparent = EffectParams { mask: MaskParams { mask_exclude_transparent: false, ..params.mask.clone() }, ..params };
+        let result = apply(&image, &sort_transparent);
+        assert!(image.enumerate_pixels().any(|(x, y, p)| p.0[3] == 0 && result.get_pixel(x, y) != p));
+    }
+
+    #[test]
+    fn added_pixels_can_keep_alpha() {
+        let image = RgbaImage::from_fn(40, 30, |x, y| image::Rgba([(x * 6) as u8, (y * 8) as u8, 0, 100 + (x % 5) as u8]));
+        let mut params = sort_everything();
+        params.random_prob = 1.0;
+        let opaque = apply(&image, &params);
+        assert!(opaque.pixels().all(|p| *p == image::Rgba([0, 0, 0, 255])));
+
+        params.pixel_add_keep_alpha = true;
+        let kept = apply(&image, &params);
+        let mut alphas: Vec<u8> = kept.pixels().map(|p| p.0[3]).collect();
+        let mut original: Vec<u8> = image.pixels().map(|p| p.0[3]).collect();
+        alphas.sort();
+        original.sort();
+        assert_eq!(alphas, original);
+        assert!(kept.pixels().all(|p| p.0[..3] == [0, 0, 0]));
+    }
+}
@@ -1,54 +1,578 @@
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
 use image::{ImageBuffer, Luma, Rgba};
+use image::imageops::{resize, FilterType};
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::edges::{gradient_magnitude, hysteresis, EdgeOperator};
+use crate::pixel::ColorValueChoice;
 
 #[derive(Debug)]
+#[derive(Clone)]
 #[derive(PartialEq)]
+// Stored in presets by the same name the command line uses
+#[derive(Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
 pub enum MaskFuncChoice {
-    Luminance,
-    Hue,
-    BrokenHue,
-    Red,
-    Green,
-    Blue,
-    ColorSum
+    Color(ColorValueChoice),
+    SobelEdges,
+    ScharrEdges,
 }
 
 impl MaskFuncChoice {
     pub fn get_range(&self) -> (f64, f64) {
         match self {
-            MaskFuncChoice::Luminance => { (0.0, 255.0) }
-            MaskFuncChoice::Hue => { (0.0, 360.0) }
-            MaskFuncChoice::BrokenHue => { (0.0, 360.0) }
-            MaskFuncChoice::Red => { (0.0, 255.0 ) }
-            MaskFuncChoice::Green => { (0.0, 255.0) }
-            MaskFuncChoice::Blue => { (0.0, 255.0 ) }
-            MaskFuncChoice::ColorSum => { (0.0, 765.0) }
+            MaskFuncChoice::Color(value) => { value.get_range() }
+            MaskFuncChoice::SobelEdges => { (0.0, 255.0) }
+            MaskFuncChoice::ScharrEdges => { (0.0, 255.0) }
+        }
+    }
+
+    pub fn period(&self) -> Option<f64> {
+        match self {
+            MaskFuncChoice::Color(value) => { value.period() }
+            _ => { None }
+        }
+    }
+
+    // Needs the surrounding pixels, not just the pixel itself
+    pub fn is_neighbourhood(&self) -> bool {
+        matches!(self, MaskFuncChoice::SobelEdges | MaskFuncChoice::ScharrEdges)
+    }
+
+    // Mask function value of every pixel of the image
+    pub fn get_values(&self, image: &ImageBuffer::<Rgba<u8>, Vec<u8>>) -> ImageBuffer::<Luma<f32>, Vec<f32>> {
+        match self {
+            MaskFuncChoice::Color(value) => { value_image(image, |p| value.get_value(p)) }
+            MaskFuncChoice::SobelEdges => { gradient_magnitude(image, &EdgeOperator::Sobel) }
+            MaskFuncChoice::ScharrEdges => { gradient_magnitude(image, &EdgeOperator::Scharr) }
         }
     }
 }
 
-fn mask_pixel(v: f64, low_threshold: f64, high_threshold: f64, invert_mask: bool) -> u8 {
-    if (low_threshold < v && v < high_threshold) ^ invert_mask { 255 } else { 0 }
+impl FromStr for MaskFuncChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sobel_edges" => { Ok(MaskFuncChoice::SobelEdges) }
+            "scharr_edges" => { Ok(MaskFuncChoice::ScharrEdges) }
+            _ => { Ok(MaskFuncChoice::Color(s.parse()?)) }
+        }
+    }
 }
 
-pub fn mask_image<F: Fn(&Rgba<u8>) -> f64 + Sync + Send>(
+impl fmt::Display for MaskFuncChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MaskFuncChoice::Color(value) => { write!(f, "{}", value) }
+            MaskFuncChoice::SobelEdges => { write!(f, "sobel_edges") }
+            MaskFuncChoice::ScharrEdges => { write!(f, "scharr_edges") }
+        }
+    }
+}
+
+impl TryFrom<String> for MaskFuncChoice {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<MaskFuncChoice> for String {
+    fn from(mask_func_choice: MaskFuncChoice) -> Self {
+        mask_func_choice.to_string()
+    }
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MaskCombineChoice {
+    And,
+    Or,
+    Xor,
+    Subtract,
+    Min,
+    Max,
+}
+
+impl MaskCombineChoice {
+    // Fuzzy versions of the boolean operators, so soft masks combine smoothly
+    pub fn combine(&self, a: u8, b: u8) -> u8 {
+        let (a, b) = (a as u32, b as u32);
+        (match self {
+            MaskCombineChoice::And => { a * b / 255 }
+            MaskCombineChoice::Or => { a + b - a * b / 255 }
+            MaskCombineChoice::Xor => { a + b - 2 * (a * b / 255) }
+            MaskCombineChoice::Subtract => { a * (255 - b) / 255 }
+            MaskCombineChoice::Min => { a.min(b) }
+            MaskCombineChoice::Max => { a.max(b) }
+        }) as u8
+    }
+}
+
+impl FromStr for MaskCombineChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "and" => { Ok(MaskCombineChoice::And) }
+            "or" => { Ok(MaskCombineChoice::Or) }
+            "xor" => { Ok(MaskCombineChoice::Xor) }
+            "subtract" => { Ok(MaskCombineChoice::Subtract) }
+            "min" => { Ok(MaskCombineChoice::Min) }
+            "max" => { Ok(MaskCombineChoice::Max) }
+            _ => { Err(format!("Unknown mask operator: {}", s)) }
+        }
+    }
+}
+
+// One threshold test over a mask function
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct MaskRule {
+    pub mask_func_choice: MaskFuncChoice,
+    pub low_threshold: f64,
+    pub high_threshold: f64,
+    // Whether a value equal to the threshold is inside the range
+    pub include_low: bool,
+    pub include_high: bool,
+    pub invert_mask: bool,
+    // Only used by edge functions: values above `high_threshold` are edges, values above
+    // `low_threshold` are edges when connected to one
+    pub edge_hysteresis: bool,
+}
+
+impl Default for MaskRule {
+    fn default() -> Self {
+        Self {
+            mask_func_choice: MaskFuncChoice::Color(ColorValueChoice::Luminance),
+            low_threshold: 195.0,
+            high_threshold: 255.0,
+            include_low: false,
+            include_high: false,
+            invert_mask: false,
+            edge_hysteresis: false,
+        }
+    }
+}
+
+impl MaskRule {
+    pub fn mask_image(&self, image: &ImageBuffer::<Rgba<u8>, Vec<u8>>, feather: f64) -> ImageBuffer::<Luma<u8>, Vec<u8>> {
+        let values = self.mask_func_choice.get_values(image);
+        if self.edge_hysteresis && self.mask_func_choice.is_neighbourhood() {
+            let edges = hysteresis(&values, self.low_threshold, self.high_threshold);
+            let pixels = edges.into_iter().map(|e| if e ^ self.invert_mask { 255 } else { 0 }).collect();
+            return ImageBuffer::<Luma<u8>, Vec<u8>>::from_vec(image.width(), image.height(), pixels).unwrap();
+        }
+        mask_values(&values, self, feather)
+    }
+}
+
+// A rule combined with the mask built so far
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct MaskLayer {
+    pub combine: MaskCombineChoice,
+    #[serde(flatten)]
+    pub rule: MaskRule,
+}
+
+// Parses `operator:function:low:high` followed by any of `:invert`, `:include_low`, `:include_high`
+impl FromStr for MaskLayer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        if parts.len() < 4 {
+            return Err(format!("Expected operator:function:low:high[:invert][:include_low][:include_high], got {}", s));
+        }
+        let mut rule = MaskRule {
+            mask_func_choice: parts[1].parse()?,
+            low_threshold: parts[2].parse().map_err(|_| format!("Invalid low threshold: {}", parts[2]))?,
+            high_threshold: parts[3].parse().map_err(|_| format!("Invalid high threshold: {}", parts[3]))?,
+            ..Default::default()
+        };
+        for flag in &parts[4..] {
+            match *flag {
+                "invert" => { rule.invert_mask = true }
+                "include_low" => { rule.include_low = true }
+                "include_high" => { rule.include_high = true }
+                _ => { return Err(format!("Unknown mask layer flag: {}", flag)) }
+            }
+        }
+        Ok(MaskLayer { combine: parts[0].parse()?, rule })
+    }
+}
+
+pub fn exclude_transparent(mask: &mut ImageBuffer::<Luma<u8>, Vec<u8>>, image: &ImageBuffer::<Rgba<u8>, Vec<u8>>) {
+    mask.par_iter_mut().zip(image.par_pixels()).for_each(|(m, p)| if p.0[3] == 0 { *m = 0 });
+}
+
+pub fn combine_masks(mask: &mut ImageBuffer::<Luma<u8>, Vec<u8>>, other: &ImageBuffer::<Luma<u8>, Vec<u8>>, combine: &MaskCombineChoice) {
+    mask.par_iter_mut().zip(other.par_iter()).for_each(|(a, b)| *a = combine.combine(*a, *b));
+}
+
+// With `feather > 0` the mask fades from 0 at the thresholds to 255 at `feather` inside the range
+fn mask_pixel(v: f64, rule: &MaskRule, feather: f64) -> u8 {
+    let (mut v, low_threshold, mut high_threshold) = (v, rule.low_threshold, rule.high_threshold);
+    // On a circular scale low > high wraps around the end, e.g. hues 340..20 are the reds
+    if let Some(period) = rule.mask_func_choice.period() {
+        v = v.rem_euclid(period);
+        if high_threshold < low_threshold {
+            high_threshold += period;
+            if v < low_threshold {
+                v += period;
+            }
+        }
+    }
+
+    if feather <= 0.0 {
+        let above_low = if rule.include_low { low_threshold <= v } else { low_threshold < v };
+        let below_high = if rule.include_high { v <= high_threshold } else { v < high_threshold };
+        return if (above_low && below_high) ^ rule.invert_mask { 255 } else { 0 };
+    }
+
+    let weight = ((v - low_threshold).min(high_threshold - v) / feather).clamp(0.0, 1.0);
+    let weight = if rule.invert_mask { 1.0 - weight } else { weight };
+    (weight * 255.0).round() as u8
+}
+
+fn value_image<F: Fn(&Rgba<u8>) -> f64 + Sync + Send>(
     image: &ImageBuffer::<Rgba<u8>, Vec<u8>>,
-    low_threshold: f64,
-    high_threshold: f64,
-    invert_mask: bool,
-    mask_function: F,
-) -> ImageBuffer::<Luma<u8>, Vec<u8>> {
+    value_function: F,
+) -> ImageBuffer::<Luma<f32>, Vec<f32>> {
     let (width, height) = image.dimensions();
 
-    let pixels: Vec<u8> = (0..height).into_par_iter().flat_map(
+    let values: Vec<f32> = (0..height).into_par_iter().flat_map(
         |y| {
-            (0..width).into_iter().map(
+            (0..width).map(
                 |x| {
-                    mask_pixel(mask_function(image.get_pixel(x, y)), low_threshold, high_threshold, invert_mask)
+                    value_function(image.get_pixel(x, y)) as f32
                 }
-            ).collect::<Vec<u8>>()
+            ).collect::<Vec<f32>>()
         }
     ).collect();
 
+    ImageBuffer::<Luma<f32>, Vec<f32>>::from_vec(width, height, values).unwrap()
+}
+
+pub fn mask_values(values: &ImageBuffer::<Luma<f32>, Vec<f32>>, rule: &MaskRule, feather: f64) -> ImageBuffer::<Luma<u8>, Vec<u8>> {
+    let (width, height) = values.dimensions();
+
+    let pixels: Vec<u8> = values.as_raw().par_iter()
+        .map(|v| mask_pixel(*v as f64, rule, feather))
+        .collect();
+
     ImageBuffer::<Luma<u8>, Vec<u8>>::from_vec(width, height, pixels).unwrap()
-}
\ No newline at end of file
+}
+
+pub fn mask_image<F: Fn(&Rgba<u8>) -> f64 + Sync + Send>(
+    image: &ImageBuffer::<Rgba<u8>, Vec<u8>>,
+    low_threshold: f64,
+    high_threshold: f64,
+    invert_mask: bool,
+    feather: f64,
+    mask_function: F,
+) -> ImageBuffer::<Luma<u8>, Vec<u8>> {
+    let rule = MaskRule { low_threshold, high_threshold, invert_mask, ..Default::default() };
+    mask_values(&value_image(image, mask_function), &rule, feather)
+}
+
+// Reads a mask painted elsewhere. It's stretched to the image size if only the resolution differs.
+pub fn load_mask(path: &Path, width: u32, height: u32) -> Result<ImageBuffer::<Luma<u8>, Vec<u8>>, String> {
+    let mask = image::open(path).map_err(|e| format!("Failed to open mask {}: {}", path.display(), e))?.to_luma8();
+    let (mask_width, mask_height) = mask.dimensions();
+    if (mask_width, mask_height) == (width, height) {
+        return Ok(mask);
+    }
+
+    let aspect_difference = (mask_width as f64 / mask_height as f64) / (width as f64 / height as f64);
+    if (aspect_difference - 1.0).abs() > 0.01 {
+        return Err(format!("Mask is {}x{}, which does not match the {}x{} image", mask_width, mask_height, width, height));
+    }
+    Ok(resize(&mask, width, height, FilterType::Triangle))
+}
+
+// Strokes painted over a generated mask. Included areas are forced into the mask, excluded ones out of it.
+#[derive(Clone)]
+pub struct MaskOverride {
+    include: ImageBuffer::<Luma<u8>, Vec<u8>>,
+    exclude: ImageBuffer::<Luma<u8>, Vec<u8>>,
+}
+
+impl MaskOverride {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            include: ImageBuffer::new(width, height),
+            exclude: ImageBuffer::new(width, height),
+        }
+    }
+
+    // `hardness` is the part of the radius painted at full strength, the rest fades out
+    pub fn stamp(&mut self, center_x: f32, center_y: f32, radius: f32, hardness: f32, exclude: bool) {
+        let (width, height) = self.include.dimensions();
+        let (layer, other) = if exclude { (&mut self.exclude, &mut self.include) } else { (&mut self.include, &mut self.exclude) };
+
+        let x_range = (center_x - radius).floor().max(0.0) as u32..((center_x + radius).ceil().max(0.0) as u32).min(width);
+        let y_range = (center_y - radius).floor().max(0.0) as u32..((center_y + radius).ceil().max(0.0) as u32).min(height);
+        for y in y_range {
+            for x in x_range.clone() {
+                let distance = ((x as f32 + 0.5 - center_x).powi(2) + (y as f32 + 0.5 - center_y).powi(2)).sqrt() / radius;
+                let strength = if distance >= 1.0 {
+                    0.0
+                } else if distance <= hardness {
+                    1.0
+                } else {
+                    (1.0 - distance) / (1.0 - hardness)
+                };
+                let value = (strength * 255.0).round() as u8;
+                let painted = layer.get_pixel_mut(x, y);
+                painted.0[0] = painted.0[0].max(value);
+                let opposite = other.get_pixel_mut(x, y);
+                opposite.0[0] = opposite.0[0].min(255 - value);
+            }
+        }
+    }
+
+    pub fn apply(&self, mask: &mut ImageBuffer::<Luma<u8>, Vec<u8>>) {
+        if self.include.dimensions() != mask.dimensions() {
+            return self.resized(mask.width(), mask.height()).apply(mask);
+        }
+
+        mask.par_iter_mut().zip(self.include.par_iter()).zip(self.exclude.par_iter()).for_each(|((m, i), e)| {
+            *m = ((*m).max(*i) as u16 * (255 - *e) as u16 / 255) as u8;
+        });
+    }
+
+    fn resized(&self, width: u32, height: u32) -> Self {
+        Self {
+            include: resize(&self.include, width, height, FilterType::Triangle),
+            exclude: resize(&self.exclude, width, height, FilterType::Triangle),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(mask_func_choice: MaskFuncChoice, low_threshold: f64, high_threshold: f64) -> MaskRule {
+        MaskRule { mask_func_choice, low_threshold, high_threshold, ..Default::default() }
+    }
+
+    #[test]
+    fn bounds_at_the_ends_of_the_range() {
+        let mut r = rule(MaskFuncChoice::Color(ColorValueChoice::Luminance), 0.0, 255.0);
+        assert_eq!(mask_pixel(0.0, &r, 0.0), 0);
+        assert_eq!(mask_pixel(255.0, &r, 0.0), 0);
+        assert_eq!(mask_pixel(128.0, &r, 0.0), 255);
+
+        r.include_low = true;
+        assert_eq!(mask_pixel(0.0, &r, 0.0), 255);
+        assert_eq!(mask_pixel(255.0, &r, 0.0), 0);
+
+        r.include_high = true;
+        assert_eq!(mask_pixel(0.0, &r, 0.0), 255);
+        assert_eq!(mask_pixel(255.0, &r, 0.0), 255);
+
+        r.invert_mask = true;
+        assert_eq!(mask_pixel(0.0, &r, 0.0), 0);
+        assert_eq!(mask_pixel(255.0, &r, 0.0), 0);
+        assert_eq!(mask_pixel(128.0, &r, 0.0), 0);
+    }
+
+    #[test]
+    fn zero_width_range() {
+        let mut r = rule(MaskFuncChoice::Color(ColorValueChoice::Red), 0.0, 0.0);
+        assert_eq!(mask_pixel(0.0, &r, 0.0), 0);
+        r.include_low = true;
+        r.include_high = true;
+        assert_eq!(mask_pixel(0.0, &r, 0.0), 255);
+        assert_eq!(mask_pixel(1.0, &r, 0.0), 0);
+    }
+
+    #[test]
+    fn hue_range_wraps_around() {
+        let mut r = rule(MaskFuncChoice::Color(ColorValueChoice::Hue), 340.0, 20.0);
+        for (hue, inside) in [(350.0, true), (0.0, true), (360.0, true), (-10.0, true), (19.0, true), (20.0, false), (340.0, false), (339.0, false), (180.0, false)] {
+            assert_eq!(mask_pixel(hue, &r, 0.0), if inside { 255 } else { 0 }, "{}", hue);
+        }
+
+        r.invert_mask = true;
+        for (hue, inside) in [(350.0, true), (0.0, true), (19.0, true), (20.0, false), (340.0, false), (180.0, false)] {
+            assert_eq!(mask_pixel(hue, &r, 0.0), if inside { 0 } else { 255 }, "{}", hue);
+        }
+
+        r.invert_mask = false;
+        r.include_low = true;
+        r.include_high = true;
+        assert_eq!(mask_pixel(340.0, &r, 0.0), 255);
+        assert_eq!(mask_pixel(20.0, &r, 0.0), 255);
+    }
+
+    #[test]
+    fn wrapped_range_with_feather() {
+        let mut r = rule(MaskFuncChoice::Color(ColorValueChoice::Hue), 340.0, 20.0);
+        assert_eq!(mask_pixel(0.0, &r, 10.0), 255);
+        assert_eq!(mask_pixel(345.0, &r, 10.0), 128);
+        assert_eq!(mask_pixel(15.0, &r, 10.0), 128);
+        assert_eq!(mask_pixel(340.0, &r, 10.0), 0);
+        assert_eq!(mask_pixel(180.0, &r, 10.0), 0);
+
+        r.invert_mask = true;
+        assert_eq!(mask_pixel(0.0, &r, 10.0), 0);
+        assert_eq!(mask_pixel(345.0, &r, 10.0), 128);
+        assert_eq!(mask_pixel(180.0, &r, 10.0), 255);
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for choice in [MaskFuncChoice::Color(ColorValueChoice::Hue), MaskFuncChoice::Color(ColorValueChoice::DistanceTo([1, 128, 255])), MaskFuncChoice::SobelEdges, MaskFuncChoice::ScharrEdges] {
+            let json = serde_json::to_string(&choice).unwrap();
+            assert_eq!(json, format!("\"{}\"", choice));
+            assert_eq!(serde_json::from_str::<MaskFuncChoice>(&json).unwrap(), choice);
+        }
+        assert_eq!("quantized_hue=3".parse::<MaskFuncChoice>(), Ok(MaskFuncChoice::Color(ColorValueChoice::QuantizedHue { buckets: 3 })));
+        assert_eq!(MaskFuncChoice::SobelEdges.to_string(), "sobel_edges");
+        assert!(serde_json::from_str::<MaskFuncChoice>("\"nope\"").is_err());
+    }
+
+    #[test]
+    fn mask_layers_parse() {
+        assert_eq!("and:hue:180:260".parse::<MaskLayer>(), Ok(MaskLayer { combine: MaskCombineChoice::And, rule: rule(MaskFuncChoice::Color(ColorValueChoice::Hue), 180.0, 260.0) }));
+
+        let layer: MaskLayer = "subtract:distance_to=#ff8000:0:20.5:include_high:invert".parse().unwrap();
+        assert_eq!(layer.combine, MaskCombineChoice::Subtract);
+        assert_eq!(layer.rule, MaskRule { include_high: true, invert_mask: true, ..rule(MaskFuncChoice::Color(ColorValueChoice::DistanceTo([255, 128, 0])), 0.0, 20.5) });
+
+        let layer: MaskLayer = "max:quantized_hue=6:0:120:include_low".parse().unwrap();
+        assert_eq!(layer.rule, MaskRule { include_low: true, ..rule(MaskFuncChoice::Color(ColorValueChoice::QuantizedHue { buckets: 6 }), 0.0, 120.0) });
+
+        for bad in ["and:hue:180", "nand:hue:180:260", "and:hue:low:260", "and:hue:180:high", "and:nope:0:1", "and:hue:180:260:inverted", "or:red:0:10:invert:"] {
+            assert!(bad.parse::<MaskLayer>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn combine_operators() {
+        let a = ImageBuffer::<Luma<u8>, Vec<u8>>::from_vec(9, 1, vec![0, 0, 0, 128, 128, 128, 255, 255, 255]).unwrap();
+        let b = ImageBuffer::<Luma<u8>, Vec<u8>>::from_vec(9, 1, vec![0, 128, 255, 0, 128, 255, 0, 128, 255]).unwrap();
+        let table = [
+            (MaskCombineChoice::And, [0, 0, 0, 0, 64, 128, 0, 128, 255]),
+            (MaskCombineChoice::Or, [0, 128, 255, 128, 192, 255, 255, 255, 255]),
+            (MaskCombineChoice::Xor, [0, 128, 255, 128, 128, 127, 255, 127, 0]),
+            (MaskCombineChoice::Subtract, [0, 0, 0, 128, 63, 0, 255, 127, 0]),
+            (MaskCombineChoice::Min, [0, 0, 0, 0, 128, 128, 0, 128, 255]),
+            (MaskCombineChoice::Max, [0, 128, 255, 128, 128, 255, 255, 255, 255]),
+        ];
+        for (combine, expected) in table {
+            let mut mask = a.clone();
+            combine_masks(&mut mask, &b, &combine);
+            assert_eq!(mask.as_raw(), &expected.to_vec(), "{:?}", combine);
+        }
+    }
+
+    fn gray(width: u32, height: u32, value: u8) -> ImageBuffer::<Luma<u8>, Vec<u8>> {
+        ImageBuffer::from_pixel(width, height, Luma([value]))
+    }
+
+    #[test]
+    fn brush_stamps() {
+        let mut strokes = MaskOverride::new(10, 10);
+        strokes.stamp(5.0, 5.0, 2.0, 1.0, false);
+        assert_eq!(strokes.include.get_pixel(5, 5).0[0], 255);
+        assert_eq!(strokes.include.get_pixel(3, 5).0[0], 255);
+        assert_eq!(strokes.include.get_pixel(2, 5).0[0], 0);
+        assert_eq!(strokes.include.get_pixel(3, 3).0[0], 0);
+
+        // Soft brushes fade out past `hardness`
+        strokes.stamp(0.0, 0.0, 4.0, 0.0, false);
+        assert_eq!(strokes.include.get_pixel(1, 1).0[0], 120);
+        assert_eq!(strokes.include.get_pixel(3, 3).0[0], 0);
+
+        // Excluding erases the opposite stroke under the brush
+        strokes.stamp(5.0, 5.0, 1.0, 1.0, true);
+        assert_eq!(strokes.exclude.get_pixel(5, 5).0[0], 255);
+        assert_eq!(strokes.include.get_pixel(5, 5).0[0], 0);
+        assert_eq!(strokes.include.get_pixel(3, 5).0[0], 255);
+
+        // Brushes partly or fully outside the image
+        strokes.stamp(-3.0, 12.0, 2.0, 1.0, false);
+        strokes.stamp(9.5, -0.5, 2.0, 1.0, true);
+        assert_eq!(strokes.exclude.get_pixel(9, 0).0[0], 255);
+    }
+
+    #[test]
+    fn strokes_override_the_mask() {
+        let mut strokes = MaskOverride::new(8, 1);
+        strokes.stamp(1.0, 0.5, 1.0, 1.0, false);
+        strokes.stamp(7.0, 0.5, 1.0, 1.0, true);
+        let mut mask = gray(8, 1, 100);
+        strokes.apply(&mut mask);
+        assert_eq!(mask.as_raw(), &vec![255, 255, 100, 100, 100, 100, 0, 0]);
+
+        // Soft exclusion scales the mask down
+        let mut soft = MaskOverride::new(1, 1);
+        soft.exclude.put_pixel(0, 0, Luma([51]));
+        let mut mask = gray(1, 1, 200);
+        soft.apply(&mut mask);
+        assert_eq!(mask.get_pixel(0, 0).0[0], 160);
+    }
+
+    #[test]
+    fn strokes_stretch_to_the_mask_size() {
+        let mut strokes = MaskOverride::new(4, 4);
+        for y in 0..4 {
+            strokes.include.put_pixel(0, y, Luma([255]));
+            strokes.exclude.put_pixel(3, y, Luma([255]));
+        }
+        let resized = strokes.resized(16, 16);
+        assert_eq!(resized.include.dimensions(), (16, 16));
+        assert_eq!(resized.include.get_pixel(0, 8).0[0], 255);
+        assert_eq!(resized.include.get_pixel(8, 8).0[0], 0);
+        assert_eq!(resized.exclude.get_pixel(15, 8).0[0], 255);
+
+        // Applying to a mask of another size stretches the strokes the same way
+        let mut mask = gray(16, 16, 100);
+        strokes.apply(&mut mask);
+        assert_eq!((mask.get_pixel(0, 8).0[0], mask.get_pixel(8, 8).0[0], mask.get_pixel(15, 8).0[0]), (255, 100, 0));
+    }
+
+    #[test]
+    fn loaded_masks_must_match_the_aspect_ratio() {
+        let path = std::env::temp_dir().join(format!("pixel_sorting_mask_{}.png", std::process::id()));
+        gray(200, 100, 255).save(&path).unwrap();
+
+        assert_eq!(load_mask(&path, 200, 100).unwrap().dimensions(), (200, 100));
+        let stretched = load_mask(&path, 400, 200).unwrap();
+        assert_eq!(stretched.dimensions(), (400, 200));
+        assert!(stretched.pixels().all(|p| p.0[0] == 255));
+        // Off by less than 1%, e.g. after rounding when the image was scaled
+        assert_eq!(load_mask(&path, 401, 200).unwrap().dimensions(), (401, 200));
+        assert!(load_mask(&path, 410, 200).is_err());
+        assert!(load_mask(&path, 100, 100).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(load_mask(&path, 200, 100).is_err());
+    }
+
+    #[test]
+    fn non_circular_values_do_not_wrap() {
+        let r = rule(MaskFuncChoice::Color(ColorValueChoice::Luminance), 200.0, 50.0);
+        for v in [0.0, 100.0, 255.0] {
+            assert_eq!(mask_pixel(v, &r, 0.0), 0);
+        }
+    }
+}
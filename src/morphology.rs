@@ -0,0 +1,234 @@
+use std::collections::VecDeque;
+use std::str::FromStr;
+use image::{ImageBuffer, Luma};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MorphologyChoice {
+    // Grows the masked areas
+    Dilate,
+    // Shrinks the masked areas
+    Erode,
+    // Erode then dilate, removes specks and thin lines
+    Open,
+    // Dilate then erode, fills small holes and gaps
+    Close,
+}
+
+impl MorphologyChoice {
+    // Works on soft masks too, using a square of `2 * radius + 1` pixels
+    pub fn apply(&self, mask: &ImageBuffer::<Luma<u8>, Vec<u8>>, radius: u32) -> ImageBuffer::<Luma<u8>, Vec<u8>> {
+        if radius == 0 {
+            return mask.clone();
+        }
+        match self {
+            MorphologyChoice::Dilate => { rank_filter(mask, radius, true) }
+            MorphologyChoice::Erode => { rank_filter(mask, radius, false) }
+            MorphologyChoice::Open => { rank_filter(&rank_filter(mask, radius, false), radius, true) }
+            MorphologyChoice::Close => { rank_filter(&rank_filter(mask, radius, true), radius, false) }
+        }
+    }
+}
+
+impl FromStr for MorphologyChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dilate" => { Ok(MorphologyChoice::Dilate) }
+            "erode" => { Ok(MorphologyChoice::Erode) }
+            "open" => { Ok(MorphologyChoice::Open) }
+            "close" => { Ok(MorphologyChoice::Close) }
+            _ => { Err(format!("Unknown morphology operation: {}", s)) }
+        }
+    }
+}
+
+// One cleanup operation, steps run one after another so e.g. a close can be followed by a wider open
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct MorphologyStep {
+    pub operation: MorphologyChoice,
+    // In pixels
+    pub radius: u32,
+}
+
+// Parses `operation` or `operation:radius`, the radius is 1 when left out
+impl FromStr for MorphologyStep {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (operation, radius) = s.split_once(':').unwrap_or((s, "1"));
+        Ok(MorphologyStep {
+            operation: operation.parse()?,
+            radius: radius.parse().map_err(|_| format!("Invalid morphology radius: {}", radius))?,
+        })
+    }
+}
+
+pub fn apply_steps(mask: &ImageBuffer::<Luma<u8>, Vec<u8>>, steps: &[MorphologyStep]) -> ImageBuffer::<Luma<u8>, Vec<u8>> {
+    steps.iter().fold(mask.clone(), |mask, step| step.operation.apply(&mask, step.radius))
+}
+
+// Max (or min) over the square around every pixel, done as a horizontal and then a vertical pass
+fn rank_filter(mask: &ImageBuffer::<Luma<u8>, Vec<u8>>, radius: u32, max: bool) -> ImageBuffer::<Luma<u8>, Vec<u8>> {
+    let (width, height) = mask.dimensions();
+    let pick = |a: u8, b: u8| if max { a.max(b) } else { a.min(b) };
+    let window = |values: &[u8], i: usize| {
+        let from = i.saturating_sub(radius as usize);
+        let to = (i + radius as usize).min(values.len() - 1);
+        values[from..=to].iter().copied().reduce(pick).unwrap()
+    };
+
+    let rows: Vec<u8> = mask.as_raw().par_chunks(width as usize).flat_map(
+        |row| (0..row.len()).map(|x| window(row, x)).collect::<Vec<u8>>()
+    ).collect();
+
+    let columns: Vec<Vec<u8>> = (0..width).into_par_iter().map(|x| {
+        let column: Vec<u8> = (0..height).map(|y| rows[(y * width + x) as usize]).collect();
+        (0..column.len()).map(|y| window(&column, y)).collect()
+    }).collect();
+
+    ImageBuffer::<Luma<u8>, Vec<u8>>::from_fn(width, height, |x, y| Luma([columns[x as usize][y as usize]]))
+}
+
+// Clears 8-connected groups of masked pixels smaller than `min_size` pixels
+pub fn remove_small_components(mask: &mut ImageBuffer::<Luma<u8>, Vec<u8>>, min_size: u32) {
+    if min_size <= 1 {
+        return;
+    }
+    let (width, height) = mask.dimensions();
+    let mut visited = vec![false; (width * height) as usize];
+    let mut queue: VecDeque<(u32, u32)> = VecDeque::new();
+
+    for start in 0..(width * height) {
+        if visited[start as usize] || mask.as_raw()[start as usize] == 0 {
+            continue;
+        }
+        visited[start as usize] = true;
+        queue.push_back((start % width, start / width));
+        let mut component: Vec<(u32, u32)> = vec![];
+
+        while let Some((x, y)) = queue.pop_front() {
+            component.push((x, y));
+            for ny in y.saturating_sub(1)..=(y + 1).min(height - 1) {
+                for nx in x.saturating_sub(1)..=(x + 1).min(width - 1) {
+                    let i = (ny * width + nx) as usize;
+                    if !visited[i] && mask.as_raw()[i] > 0 {
+                        visited[i] = true;
+                        queue.push_back((nx, ny));
+                    }
+                }
+            }
+        }
+
+        if component.len() < min_size as usize {
+            for (x, y) in component {
+                mask.put_pixel(x, y, Luma([0]));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mask_from(rows: &[&str]) -> ImageBuffer::<Luma<u8>, Vec<u8>> {
+        ImageBuffer::<Luma<u8>, Vec<u8>>::from_fn(rows[0].len() as u32, rows.len() as u32, |x, y| {
+            Luma([if rows[y as usize].as_bytes()[x as usize] == b'#' { 255 } else { 0 }])
+        })
+    }
+
+    #[test]
+    fn dilate_clips_at_the_border() {
+        let mask = mask_from(&["#....", ".....", "....."]);
+        assert_eq!(MorphologyChoice::Dilate.apply(&mask, 1), mask_from(&["##...", "##...", "....."]));
+        let mask = mask_from(&[".....", "..#..", "....."]);
+        assert_eq!(MorphologyChoice::Dilate.apply(&mask, 1), mask_from(&[".###.", ".###.", ".###."]));
+        assert_eq!(MorphologyChoice::Dilate.apply(&mask, 5), mask_from(&["#####", "#####", "#####"]));
+    }
+
+    #[test]
+    fn erode_keeps_areas_touching_the_border() {
+        let full = mask_from(&["####", "####", "####"]);
+        assert_eq!(MorphologyChoice::Erode.apply(&full, 2), full);
+        let mask = mask_from(&["###..", "###..", "###.."]);
+        assert_eq!(MorphologyChoice::Erode.apply(&mask, 1), mask_from(&["##...", "##...", "##..."]));
+    }
+
+    #[test]
+    fn open_and_close() {
+        let speck = mask_from(&["......", ".#....", "...###", "...###", "...###"]);
+        assert_eq!(MorphologyChoice::Open.apply(&speck, 1), mask_from(&["......", "......", "...###", "...###", "...###"]));
+        let hole = mask_from(&["#####", "#####", "##.##", "#####", "#####"]);
+        assert_eq!(MorphologyChoice::Close.apply(&hole, 1), mask_from(&["#####", "#####", "#####", "#####", "#####"]));
+        assert_eq!(MorphologyChoice::Open.apply(&speck, 0), speck);
+        assert_eq!(apply_steps(&speck, &[]), speck);
+    }
+
+    #[test]
+    fn steps_run_in_order() {
+        let step = |operation: MorphologyChoice, radius: u32| MorphologyStep { operation, radius };
+        let mask = mask_from(&[".......", ".......", "...#...", ".......", "......."]);
+        // Dilating first keeps the grown pixel, eroding first removes it for good
+        assert_eq!(apply_steps(&mask, &[step(MorphologyChoice::Dilate, 1), step(MorphologyChoice::Erode, 1)]), mask);
+        assert_eq!(apply_steps(&mask, &[step(MorphologyChoice::Erode, 1), step(MorphologyChoice::Dilate, 1)]), mask_from(&["......."; 5]));
+        // Radii add up
+        assert_eq!(apply_steps(&mask, &[step(MorphologyChoice::Dilate, 1), step(MorphologyChoice::Dilate, 2)]), MorphologyChoice::Dilate.apply(&mask, 3));
+    }
+
+    #[test]
+    fn steps_parse() {
+        assert_eq!("close".parse::<MorphologyStep>(), Ok(MorphologyStep { operation: MorphologyChoice::Close, radius: 1 }));
+        assert_eq!("open:4".parse::<MorphologyStep>(), Ok(MorphologyStep { operation: MorphologyChoice::Open, radius: 4 }));
+        for bad in ["none", "close:", "close:-1", "close:big", "blur:2"] {
+            assert!(bad.parse::<MorphologyStep>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn rank_filter_keeps_soft_values() {
+        let mask = ImageBuffer::<Luma<u8>, Vec<u8>>::from_vec(3, 1, vec![0, 100, 40]).unwrap();
+        assert_eq!(MorphologyChoice::Dilate.apply(&mask, 1).into_raw(), vec![100, 100, 100]);
+        assert_eq!(MorphologyChoice::Erode.apply(&mask, 1).into_raw(), vec![0, 0, 40]);
+    }
+
+    #[test]
+    fn small_components_are_removed() {
+        // Two pixels in a corner, three along the right border, a diagonal pair and a single pixel
+        let mut mask = mask_from(&["##....#", "......#", "...#..#", "....#..", "#......"]);
+        remove_small_components(&mut mask, 3);
+        assert_eq!(mask, mask_from(&["......#", "......#", "......#", ".......", "......."]));
+    }
+
+    #[test]
+    fn components_connect_diagonally() {
+        let diagonal = mask_from(&["#...", ".#..", "..#.", "...."]);
+        let mut mask = diagonal.clone();
+        remove_small_components(&mut mask, 3);
+        assert_eq!(mask, diagonal);
+        remove_small_components(&mut mask, 4);
+        assert_eq!(mask, mask_from(&["....", "....", "....", "...."]));
+    }
+
+    #[test]
+    fn small_component_limits() {
+        let single = mask_from(&["...", ".#.", "..."]);
+        for min_size in [0, 1] {
+            let mut mask = single.clone();
+            remove_small_components(&mut mask, min_size);
+            assert_eq!(mask, single);
+        }
+        let mut soft = ImageBuffer::<Luma<u8>, Vec<u8>>::from_vec(3, 1, vec![1, 200, 0]).unwrap();
+        remove_small_components(&mut soft, 2);
+        assert_eq!(soft.into_raw(), vec![1, 200, 0]);
+    }
+}
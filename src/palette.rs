@@ -0,0 +1,205 @@
+use std::cmp::Reverse;
+use std::fs;
+use std::path::Path;
+use image::{ImageBuffer, Rgba};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use crate::pixel::parse_hex_color;
+
+// Pixels looked at when counting colors, bigger images are sampled evenly
+const MAX_SAMPLES: usize = 50_000;
+const KMEANS_ITERATIONS: usize = 15;
+
+// Reads a GIMP `.gpl` palette or a list of `#rrggbb` colors, one per line
+pub fn load_palette(path: &Path) -> Result<Vec<[u8; 3]>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("Failed to read palette {}: {}", path.display(), e))?;
+    let colors = if text.trim_start().starts_with("GIMP Palette") { parse_gpl(&text)? } else { parse_hex_list(&text)? };
+    if colors.is_empty() {
+        return Err(format!("No colors in palette {}", path.display()));
+    }
+    Ok(colors)
+}
+
+fn parse_gpl(text: &str) -> Result<Vec<[u8; 3]>, String> {
+    let mut colors = vec![];
+    for (i, line) in text.lines().enumerate().skip(1) {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with("Name:") || line.starts_with("Columns:") {
+            continue;
+        }
+        let channels: Vec<u8> = line.split_whitespace().take(3).map_while(|c| c.parse().ok()).collect();
+        if channels.len() != 3 {
+            return Err(format!("Invalid color on line {}: {}", i + 1, line));
+        }
+        colors.push([channels[0], channels[1], channels[2]]);
+    }
+    Ok(colors)
+}
+
+// Anything after the color on a line is ignored, so names can follow it
+fn parse_hex_list(text: &str) -> Result<Vec<[u8; 3]>, String> {
+    text.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with("//") && !line.starts_with(';'))
+        .map(|line| parse_hex_color(line.split(|c: char| c.is_whitespace() || c == ',').next().unwrap()))
+        .collect()
+}
+
+fn samples(image: &ImageBuffer::<Rgba<u8>, Vec<u8>>) -> Vec<[f32; 3]> {
+    let step = (image.pixels().len() / MAX_SAMPLES).max(1);
+    image.pixels().step_by(step).map(|p| [p.0[0] as f32, p.0[1] as f32, p.0[2] as f32]).collect()
+}
+
+fn distance(a: &[f32; 3], b: &[f32; 3]) -> f32 {
+    (a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)
+}
+
+fn nearest(color: &[f32; 3], centers: &[[f32; 3]]) -> usize {
+    (0..centers.len()).min_by(|a, b| distance(color, &centers[*a]).total_cmp(&distance(color, &centers[*b]))).unwrap()
+}
+
+// The `k` main colors of the image found with k-means, with how many pixels each one stands for.
+// Most common colors come first.
+pub fn extract_palette(image: &ImageBuffer::<Rgba<u8>, Vec<u8>>, k: usize, seed: u64) -> (Vec<[u8; 3]>, Vec<f64>) {
+    let samples = samples(image);
+    if samples.is_empty() || k == 0 {
+        return (vec![], vec![]);
+    }
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+
+    // k-means++ start: every next center is picked far from the ones before
+    let mut centers = vec![samples[rng.random_range(0..samples.len())]];
+    while centers.len() < k.min(samples.len()) {
+        let distances: Vec<f32> = samples.iter().map(|s| distance(s, &centers[nearest(s, &centers)])).collect();
+        let total: f32 = distances.iter().sum();
+        if total <= 0.0 {
+            break;
+        }
+        let mut target = rng.random_range(0.0..total);
+        let index = distances.iter().position(|d| { target -= d; target < 0.0 }).unwrap_or(samples.len() - 1);
+        centers.push(samples[index]);
+    }
+
+    let mut counts = vec![0_usize; centers.len()];
+    for _ in 0..KMEANS_ITERATIONS {
+        let mut sums = vec![[0.0_f32; 3]; centers.len()];
+        counts = vec![0; centers.len()];
+        for s in &samples {
+            let i = nearest(s, &centers);
+            counts[i] += 1;
+            for c in 0..3 {
+                sums[i][c] += s[c];
+            }
+        }
+        for i in 0..centers.len() {
+            if counts[i] > 0 {
+                centers[i] = sums[i].map(|v| v / counts[i] as f32);
+            }
+        }
+    }
+
+    let mut clusters: Vec<([u8; 3], usize)> = centers.iter()
+        .map(|c| c.map(|v| v.round() as u8))
+        .zip(counts)
+        .collect();
+    clusters.sort_by_key(|(_, count)| Reverse(*count));
+    clusters.into_iter().map(|(color, count)| (color, count as f64)).unzip()
+}
+
+// How many pixels of the image are closest to each of the colors
+pub fn color_frequencies(image: &ImageBuffer::<Rgba<u8>, Vec<u8>>, colors: &[[u8; 3]]) -> Vec<f64> {
+    let centers: Vec<[f32; 3]> = colors.iter().map(|c| c.map(|v| v as f32)).collect();
+    let mut counts = vec![0.0; colors.len()];
+    if !centers.is_empty() {
+        for s in samples(image) {
+            counts[nearest(&s, &centers)] += 1.0;
+        }
+    }
+    counts
+}
+
+// Random palette color, `weights` of the same length as `colors` make some colors more likely
+pub fn pick_color<R: Rng>(colors: &[[u8; 3]], weights: &[f64], rng: &mut R) -> Rgba<u8> {
+    if colors.is_empty() {
+        return Rgba([0, 0, 0, 255]);
+    }
+    let total: f64 = weights.iter().sum();
+    let index = if weights.len() == colors.len() && total > 0.0 {
+        let mut target = rng.random_range(0.0..total);
+        weights.iter().position(|w| { target -= w; target < 0.0 }).unwrap_or(colors.len() - 1)
+    } else {
+        rng.random_range(0..colors.len())
+    };
+    let [r, g, b] = colors[index];
+    Rgba([r, g, b, 255])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gpl_palettes() {
+        let text = "GIMP Palette\r\nName: Test\r\nColumns: 2\r\n# a comment\r\n\r\n255   0   0\tRed\r\n  0 128 255 Sky blue\r\n";
+        assert_eq!(parse_gpl(text), Ok(vec![[255, 0, 0], [0, 128, 255]]));
+        assert!(parse_gpl("GIMP Palette\n12 34\n").is_err());
+        assert!(parse_gpl("GIMP Palette\n300 0 0\n").is_err());
+        assert!(parse_gpl("GIMP Palette\nred green blue\n").is_err());
+        assert_eq!(parse_gpl("GIMP Palette\nName: Empty\n"), Ok(vec![]));
+    }
+
+    #[test]
+    fn hex_list_palettes() {
+        let text = "// warm colors\r\n#ff0000\r\nFFA500 orange\r\n; comment\r\n\r\n#00ff00,green\n";
+        assert_eq!(parse_hex_list(text), Ok(vec![[255, 0, 0], [255, 165, 0], [0, 255, 0]]));
+        assert!(parse_hex_list("#ff00\n").is_err());
+        assert!(parse_hex_list("#gg0000\n").is_err());
+        assert!(parse_hex_list("#ff0000\nnot a color\n").is_err());
+    }
+
+    fn four_blocks() -> ImageBuffer::<Rgba<u8>, Vec<u8>> {
+        let colors = [[200, 30, 30], [20, 20, 220], [240, 240, 240], [10, 10, 10]];
+        // Blocks of 16, 8, 4 and 4 columns
+        ImageBuffer::<Rgba<u8>, Vec<u8>>::from_fn(32, 8, |x, _| {
+            let [r, g, b] = colors[match x { 0..=15 => 0, 16..=23 => 1, 24..=27 => 2, _ => 3 }];
+            Rgba([r, g, b, 255])
+        })
+    }
+
+    #[test]
+    fn kmeans_finds_the_main_colors() {
+        let (colors, weights) = extract_palette(&four_blocks(), 4, 3);
+        assert_eq!(colors[..2], [[200, 30, 30], [20, 20, 220]]);
+        let mut small = colors[2..].to_vec();
+        small.sort();
+        assert_eq!(small, vec![[10, 10, 10], [240, 240, 240]]);
+        assert_eq!(weights, vec![128.0, 64.0, 32.0, 32.0]);
+
+        // Only four distinct colors, so asking for more can't give more
+        assert_eq!(extract_palette(&four_blocks(), 10, 3).0.len(), 4);
+        assert_eq!(extract_palette(&four_blocks(), 0, 3), (vec![], vec![]));
+    }
+
+    #[test]
+    fn kmeans_is_deterministic_for_a_seed() {
+        let image = ImageBuffer::<Rgba<u8>, Vec<u8>>::from_fn(64, 64, |x, y| Rgba([(x * 4) as u8, (y * 4) as u8, ((x + y) * 2) as u8, 255]));
+        let first = extract_palette(&image, 5, 11);
+        assert_eq!(first.0.len(), 5);
+        assert_eq!(first, extract_palette(&image, 5, 11));
+    }
+
+    #[test]
+    fn zero_weight_colors_are_never_picked() {
+        let colors = [[1, 1, 1], [2, 2, 2], [3, 3, 3]];
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        for _ in 0..1000 {
+            assert_eq!(pick_color(&colors, &[0.0, 1.0, 0.0], &mut rng), Rgba([2, 2, 2, 255]));
+            assert_ne!(pick_color(&colors, &[0.0, 3.0, 1.0], &mut rng), Rgba([1, 1, 1, 255]));
+        }
+        // Weights that don't match the colors are ignored
+        let picked: Vec<Rgba<u8>> = (0..300).map(|_| pick_color(&colors, &[1.0], &mut rng)).collect();
+        assert!(colors.iter().all(|[v, _, _]| picked.contains(&Rgba([*v, *v, *v, 255]))));
+        assert_eq!(pick_color(&[], &[], &mut rng), Rgba([0, 0, 0, 255]));
+    }
+}
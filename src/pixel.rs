@@ -1,60 +1,337 @@
+use std::fmt;
+use std::str::FromStr;
 use colors_transform::{Color, Rgb};
 use image::Rgba;
+use serde::{Deserialize, Serialize};
 
 
 #[derive(Debug)]
+#[derive(Clone)]
 #[derive(PartialEq)]
-pub enum PixelSortKeyChoice {
+// Stored in presets by the same name the command line uses
+#[derive(Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+// Value computed from a single pixel, used both as a sort key and as a mask function
+pub enum ColorValueChoice {
     Hue,
-    BrokenHue,
+    // Hue snapped to the nearest of `buckets` evenly spaced hues, 3 buckets gives the old "broken hue" look
+    QuantizedHue { buckets: u16 },
     Luminance,
+    // Luminance reduced to `levels` evenly spaced values
+    PosterizedLuminance { levels: u16 },
     Red,
     Green,
     Blue,
-    ColorSum
+    ColorSum,
+    Alpha,
+    HslSaturation,
+    HsvSaturation,
+    HsvValue,
+    LabLightness,
+    LabA,
+    LabB,
+    OklabLightness,
+    OklchChroma,
+    OklchHue,
+    // OKLab distance to a color
+    DistanceTo([u8; 3]),
 }
 
-pub fn luminance(pixel: &Rgba<u8>) -> f64 {
-    0.2126 * (pixel.0[0] as f64) + 0.7152 * (pixel.0[1] as f64) + 0.0722 * (pixel.0[2] as f64)
+// Sort keys are the plain color values
+pub type PixelSortKeyChoice = ColorValueChoice;
+
+impl ColorValueChoice {
+    pub fn get_range(&self) -> (f64, f64) {
+        match self {
+            ColorValueChoice::Hue => { (0.0, 360.0) }
+            ColorValueChoice::QuantizedHue { .. } => { (0.0, 360.0) }
+            ColorValueChoice::Luminance => { (0.0, 255.0) }
+            ColorValueChoice::PosterizedLuminance { .. } => { (0.0, 255.0) }
+            ColorValueChoice::Red => { (0.0, 255.0) }
+            ColorValueChoice::Green => { (0.0, 255.0) }
+            ColorValueChoice::Blue => { (0.0, 255.0) }
+            ColorValueChoice::ColorSum => { (0.0, 765.0) }
+            ColorValueChoice::Alpha => { (0.0, 255.0) }
+            ColorValueChoice::HslSaturation => { (0.0, 100.0) }
+            ColorValueChoice::HsvSaturation => { (0.0, 100.0) }
+            ColorValueChoice::HsvValue => { (0.0, 100.0) }
+            ColorValueChoice::LabLightness => { (0.0, 100.0) }
+            // Extremes of the sRGB gamut
+            ColorValueChoice::LabA => { (-87.0, 99.0) }
+            ColorValueChoice::LabB => { (-108.0, 95.0) }
+            ColorValueChoice::OklabLightness => { (0.0, 100.0) }
+            ColorValueChoice::OklchChroma => { (0.0, 33.0) }
+            ColorValueChoice::OklchHue => { (0.0, 360.0) }
+            ColorValueChoice::DistanceTo(_) => { (0.0, 100.0) }
+        }
+    }
+
+    // Hues go around the color wheel, so their threshold ranges can wrap past the end
+    pub fn period(&self) -> Option<f64> {
+        match self {
+            ColorValueChoice::Hue | ColorValueChoice::QuantizedHue { .. } | ColorValueChoice::OklchHue => { Some(360.0) }
+            _ => { None }
+        }
+    }
+
+    pub fn get_value(&self, p: &Rgba<u8>) -> f64 {
+        match self {
+            ColorValueChoice::Hue => { hue(p) }
+            ColorValueChoice::QuantizedHue { buckets } => { quantized_hue(p, *buckets) }
+            ColorValueChoice::Luminance => { luminance(p) }
+            ColorValueChoice::PosterizedLuminance { levels } => { posterized_luminance(p, *levels) }
+            ColorValueChoice::Red => { p.0[0] as f64 }
+            ColorValueChoice::Green => { p.0[1] as f64 }
+            ColorValueChoice::Blue => { p.0[2] as f64 }
+            ColorValueChoice::ColorSum => { p.0[0] as f64 + p.0[1] as f64 + p.0[2] as f64 }
+            ColorValueChoice::Alpha => { p.0[3] as f64 }
+            ColorValueChoice::HslSaturation => { hsl_saturation(p) }
+            ColorValueChoice::HsvSaturation => { hsv_saturation(p) }
+            ColorValueChoice::HsvValue => { hsv_value(p) }
+            ColorValueChoice::LabLightness => { lab(p)[0] }
+            ColorValueChoice::LabA => { lab(p)[1] }
+            ColorValueChoice::LabB => { lab(p)[2] }
+            ColorValueChoice::OklabLightness => { oklch(p)[0] }
+            ColorValueChoice::OklchChroma => { oklch(p)[1] }
+            ColorValueChoice::OklchHue => { oklch(p)[2] }
+            ColorValueChoice::DistanceTo(color) => { oklab_distance(p, color) }
+        }
+    }
 }
 
-pub fn some_color(pixel: &Rgba<u8>) -> i16 {
-    let red = pixel.0[0] as i16;
-    let green = pixel.0[1] as i16;
-    let blue = pixel.0[2] as i16;
+impl FromStr for ColorValueChoice {
+    type Err = String;
 
-    let min = blue.min(green.min(red));
-    let max = blue.max(green.max(red));
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hue" => { Ok(ColorValueChoice::Hue) }
+            // Name used before the bucket count could be changed, old presets still have it
+            "broken_hue" => { Ok(ColorValueChoice::QuantizedHue { buckets: 3 }) }
+            _ if s.starts_with("quantized_hue=") => { Ok(ColorValueChoice::QuantizedHue { buckets: parse_steps(&s["quantized_hue=".len()..])? }) }
+            "luminance" => { Ok(ColorValueChoice::Luminance) }
+            _ if s.starts_with("posterized_luminance=") => { Ok(ColorValueChoice::PosterizedLuminance { levels: parse_steps(&s["posterized_luminance=".len()..])? }) }
+            "red" => { Ok(ColorValueChoice::Red) }
+            "green" => { Ok(ColorValueChoice::Green) }
+            "blue" => { Ok(ColorValueChoice::Blue) }
+            "color_sum" => { Ok(ColorValueChoice::ColorSum) }
+            "alpha" => { Ok(ColorValueChoice::Alpha) }
+            "hsl_saturation" => { Ok(ColorValueChoice::HslSaturation) }
+            "hsv_saturation" => { Ok(ColorValueChoice::HsvSaturation) }
+            "hsv_value" => { Ok(ColorValueChoice::HsvValue) }
+            "lab_l" => { Ok(ColorValueChoice::LabLightness) }
+            "lab_a" => { Ok(ColorValueChoice::LabA) }
+            "lab_b" => { Ok(ColorValueChoice::LabB) }
+            "oklab_l" => { Ok(ColorValueChoice::OklabLightness) }
+            "oklch_chroma" => { Ok(ColorValueChoice::OklchChroma) }
+            "oklch_hue" => { Ok(ColorValueChoice::OklchHue) }
+            _ if s.starts_with("distance_to=") => { Ok(ColorValueChoice::DistanceTo(parse_hex_color(&s["distance_to=".len()..])?)) }
+            _ => { Err(format!("Unknown color value: {}", s)) }
+        }
+    }
+}
 
-    // println!("{:?}, {:?}, {:?}", min, max, (red,green,blue));
+impl fmt::Display for ColorValueChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ColorValueChoice::Hue => { write!(f, "hue") }
+            ColorValueChoice::QuantizedHue { buckets } => { write!(f, "quantized_hue={}", buckets) }
+            ColorValueChoice::Luminance => { write!(f, "luminance") }
+            ColorValueChoice::PosterizedLuminance { levels } => { write!(f, "posterized_luminance={}", levels) }
+            ColorValueChoice::Red => { write!(f, "red") }
+            ColorValueChoice::Green => { write!(f, "green") }
+            ColorValueChoice::Blue => { write!(f, "blue") }
+            ColorValueChoice::ColorSum => { write!(f, "color_sum") }
+            ColorValueChoice::Alpha => { write!(f, "alpha") }
+            ColorValueChoice::HslSaturation => { write!(f, "hsl_saturation") }
+            ColorValueChoice::HsvSaturation => { write!(f, "hsv_saturation") }
+            ColorValueChoice::HsvValue => { write!(f, "hsv_value") }
+            ColorValueChoice::LabLightness => { write!(f, "lab_l") }
+            ColorValueChoice::LabA => { write!(f, "lab_a") }
+            ColorValueChoice::LabB => { write!(f, "lab_b") }
+            ColorValueChoice::OklabLightness => { write!(f, "oklab_l") }
+            ColorValueChoice::OklchChroma => { write!(f, "oklch_chroma") }
+            ColorValueChoice::OklchHue => { write!(f, "oklch_hue") }
+            ColorValueChoice::DistanceTo(color) => { write!(f, "distance_to={}", format_hex_color(color)) }
+        }
+    }
+}
+
+impl TryFrom<String> for ColorValueChoice {
+    type Error = String;
 
-    if min == max {
-        return 0;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
     }
+}
 
-    let mut hue: i16;
-    if max == red {
-        hue = (green - blue) / (max - min);
-    } else if max == green {
-        hue = 2 + (blue - red) / (max - min);
-    } else {
-        hue = 4 + (red - green) / (max - min);
+impl From<ColorValueChoice> for String {
+    fn from(value: ColorValueChoice) -> Self {
+        value.to_string()
     }
+}
 
-    hue = hue * 60;
-    if hue < 0 {
-        hue = hue + 360;
+pub fn luminance(pixel: &Rgba<u8>) -> f64 {
+    0.2126 * (pixel.0[0] as f64) + 0.7152 * (pixel.0[1] as f64) + 0.0722 * (pixel.0[2] as f64)
+}
+
+// Bucket and level counts of the quantized keys
+pub fn parse_steps(s: &str) -> Result<u16, String> {
+    match s.parse::<u16>() {
+        Ok(steps) if steps >= 2 => { Ok(steps) }
+        _ => { Err(format!("Expected a whole number of at least 2, got {}", s)) }
     }
+}
 
-    // println!("{:?}", hue);
+pub fn quantized_hue(pixel: &Rgba<u8>, buckets: u16) -> f64 {
+    let step = 360.0 / buckets.max(1) as f64;
+    ((hue(pixel) / step).round() * step).rem_euclid(360.0)
+}
 
-    hue
+pub fn posterized_luminance(pixel: &Rgba<u8>, levels: u16) -> f64 {
+    let step = 255.0 / (levels.max(2) - 1) as f64;
+    (luminance(pixel) / step).round() * step
 }
 
-pub fn hue(pixel: &Rgba<u8>) -> i16 {
+pub fn hue(pixel: &Rgba<u8>) -> f64 {
     let red = pixel.0[0] as f32;
     let green = pixel.0[1] as f32;
     let blue = pixel.0[2] as f32;
 
-    Rgb::from(red, green, blue).to_hsl().get_hue().round() as i16
-}
\ No newline at end of file
+    Rgb::from(red, green, blue).to_hsl().get_hue() as f64
+}
+
+// Channels in 0..1
+fn unit_rgb(pixel: &Rgba<u8>) -> [f64; 3] {
+    [pixel.0[0] as f64 / 255.0, pixel.0[1] as f64 / 255.0, pixel.0[2] as f64 / 255.0]
+}
+
+// sRGB channels with the gamma curve removed
+fn linear_rgb(pixel: &Rgba<u8>) -> [f64; 3] {
+    unit_rgb(pixel).map(|c| if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) })
+}
+
+// 0..100
+pub fn hsl_saturation(pixel: &Rgba<u8>) -> f64 {
+    let [r, g, b] = unit_rgb(pixel);
+    let (max, min) = (r.max(g).max(b), r.min(g).min(b));
+    let lightness = (max + min) / 2.0;
+    if max == min {
+        return 0.0;
+    }
+    (max - min) / (1.0 - (2.0 * lightness - 1.0).abs()) * 100.0
+}
+
+// 0..100
+pub fn hsv_saturation(pixel: &Rgba<u8>) -> f64 {
+    let [r, g, b] = unit_rgb(pixel);
+    let (max, min) = (r.max(g).max(b), r.min(g).min(b));
+    if max == 0.0 {
+        return 0.0;
+    }
+    (max - min) / max * 100.0
+}
+
+// 0..100
+pub fn hsv_value(pixel: &Rgba<u8>) -> f64 {
+    let [r, g, b] = unit_rgb(pixel);
+    r.max(g).max(b) * 100.0
+}
+
+// CIE L*a*b* with the D65 white point, L* is 0..100
+pub fn lab(pixel: &Rgba<u8>) -> [f64; 3] {
+    let [r, g, b] = linear_rgb(pixel);
+    let x = (0.4124564 * r + 0.3575761 * g + 0.1804375 * b) / 0.95047;
+    let y = 0.2126729 * r + 0.7151522 * g + 0.0721750 * b;
+    let z = (0.0193339 * r + 0.1191920 * g + 0.9503041 * b) / 1.08883;
+
+    let f = |t: f64| if t > 216.0 / 24389.0 { t.cbrt() } else { (24389.0 / 27.0 * t + 16.0) / 116.0 };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+// OKLab scaled by 100, so lightness is 0..100 like L*
+pub fn oklab(pixel: &Rgba<u8>) -> [f64; 3] {
+    let [r, g, b] = linear_rgb(pixel);
+    let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+    let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+    let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+
+    let lightness = 0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s;
+    let a = 1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s;
+    let b = 0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s;
+    [lightness * 100.0, a * 100.0, b * 100.0]
+}
+
+// Below this chroma a and b are rounding noise, so grays get hue 0 like `hue` gives them
+const ACHROMATIC_CHROMA: f64 = 1e-4;
+
+// OKLab in polar form: lightness, chroma and hue in degrees
+pub fn oklch(pixel: &Rgba<u8>) -> [f64; 3] {
+    let [lightness, a, b] = oklab(pixel);
+    let chroma = a.hypot(b);
+    let hue = if chroma < ACHROMATIC_CHROMA { 0.0 } else { b.atan2(a).to_degrees().rem_euclid(360.0) };
+    [lightness, chroma, hue]
+}
+
+// Euclidean distance in OKLab, 0..100 with black to white being the farthest apart
+pub fn oklab_distance(pixel: &Rgba<u8>, color: &[u8; 3]) -> f64 {
+    let [l1, a1, b1] = oklab(pixel);
+    let [l2, a2, b2] = oklab(&Rgba([color[0], color[1], color[2], 255]));
+    ((l1 - l2).powi(2) + (a1 - a2).powi(2) + (b1 - b2).powi(2)).sqrt()
+}
+
+// `#rrggbb` or `rrggbb`
+pub fn parse_hex_color(s: &str) -> Result<[u8; 3], String> {
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Err(format!("Expected a #rrggbb color, got {}", s));
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| format!("Expected a #rrggbb color, got {}", s));
+    Ok([channel(0)?, channel(2)?, channel(4)?])
+}
+
+pub fn format_hex_color(color: &[u8; 3]) -> String {
+    format!("#{:02x}{:02x}{:02x}", color[0], color[1], color[2])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_round_trip() {
+        let choices = [
+            ColorValueChoice::Hue, ColorValueChoice::QuantizedHue { buckets: 7 }, ColorValueChoice::Luminance,
+            ColorValueChoice::PosterizedLuminance { levels: 4 }, ColorValueChoice::Red, ColorValueChoice::Green,
+            ColorValueChoice::Blue, ColorValueChoice::ColorSum, ColorValueChoice::Alpha,
+            ColorValueChoice::HslSaturation, ColorValueChoice::HsvSaturation, ColorValueChoice::HsvValue,
+            ColorValueChoice::LabLightness, ColorValueChoice::LabA, ColorValueChoice::LabB,
+            ColorValueChoice::OklabLightness, ColorValueChoice::OklchChroma, ColorValueChoice::OklchHue,
+            ColorValueChoice::DistanceTo([1, 128, 255]),
+        ];
+        for choice in choices {
+            let json = serde_json::to_string(&choice).unwrap();
+            assert_eq!(json, format!("\"{}\"", choice));
+            assert_eq!(choice.to_string().parse::<ColorValueChoice>(), Ok(choice.clone()));
+            assert_eq!(serde_json::from_str::<ColorValueChoice>(&json).unwrap(), choice);
+        }
+        assert_eq!(ColorValueChoice::OklabLightness.to_string(), "oklab_l");
+        assert_eq!(ColorValueChoice::DistanceTo([1, 128, 255]).to_string(), "distance_to=#0180ff");
+        assert_eq!("broken_hue".parse::<ColorValueChoice>(), Ok(ColorValueChoice::QuantizedHue { buckets: 3 }));
+        for bad in ["nope", "sobel_edges", "quantized_hue=1", "distance_to=#12345"] {
+            assert!(bad.parse::<ColorValueChoice>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn grays_have_no_oklch_hue() {
+        for v in [0, 1, 64, 128, 200, 254, 255] {
+            let [_, chroma, hue] = oklch(&Rgba([v, v, v, 255]));
+            assert!(chroma < ACHROMATIC_CHROMA, "{}", v);
+            assert_eq!(hue, 0.0, "{}", v);
+            assert_eq!(ColorValueChoice::OklchHue.get_value(&Rgba([v, v, v, 255])), 0.0);
+        }
+        let [_, _, red_hue] = oklch(&Rgba([255, 0, 0, 255]));
+        assert!((red_hue - 29.2).abs() < 0.5, "{}", red_hue);
+    }
+}
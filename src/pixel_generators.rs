@@ -1,51 +1,176 @@
+use std::fmt;
+use std::str::FromStr;
 use image::Rgba;
 use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::palette::pick_color;
+use crate::pixel::{format_hex_color, parse_hex_color};
 
 #[derive(Debug)]
+#[derive(Clone)]
 #[derive(PartialEq)]
+// Stored in presets by the same name the command line uses
+#[derive(Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
 pub enum PixelAddChoice {
     RandomPixel,
     RandomRedShade,
     RandomBlueShade,
     RandomGreenShade,
-    Black
+    Black,
+    // Random colors from a palette, `weights` are relative chances of the colors, empty for equal chances
+    Palette { colors: Vec<[u8; 3]>, weights: Vec<f64> },
+    // Gradient across the whole image, `angle` in degrees with 0 going left to right
+    LinearGradient { start: [u8; 3], end: [u8; 3], angle: f64 },
+    // Gradient from the image center to the corners
+    RadialGradient { center: [u8; 3], edge: [u8; 3] },
+    // Smooth color noise, `scale` is the feature size in pixels
+    PerlinNoise { scale: f64 },
+    // The original pixel with every channel moved randomly by up to `amount`
+    Jitter { amount: u8 },
+    Inverted,
+    // The original pixel with its channels shifted, red becomes green, green blue and blue red
+    ChannelRotated,
+}
+
+impl PixelAddChoice {
+    // `x` and `y` are the position of the replaced `original` pixel in an image of `size`
+    pub fn get_pixel<R: Rng>(&self, x: u32, y: u32, original: Rgba<u8>, size: (u32, u32), rng: &mut R) -> Rgba<u8> {
+        match self {
+            PixelAddChoice::RandomPixel => { get_random_pixel(rng) }
+            PixelAddChoice::RandomRedShade => { get_random_red_shade(rng) }
+            PixelAddChoice::RandomBlueShade => { get_random_blue_shade(rng) }
+            PixelAddChoice::RandomGreenShade => { get_random_green_shade(rng) }
+            PixelAddChoice::Black => { get_black() }
+            PixelAddChoice::Palette { colors, weights } => { pick_color(colors, weights, rng) }
+            PixelAddChoice::LinearGradient { start, end, angle } => { get_linear_gradient(x, y, size, start, end, *angle) }
+            PixelAddChoice::RadialGradient { center, edge } => { get_radial_gradient(x, y, size, center, edge) }
+            PixelAddChoice::PerlinNoise { scale } => { get_perlin_noise(x, y, *scale) }
+            PixelAddChoice::Jitter { amount } => { get_jittered(original, *amount, rng) }
+            PixelAddChoice::Inverted => { get_inverted(original) }
+            PixelAddChoice::ChannelRotated => { get_channel_rotated(original) }
+        }
+    }
+}
+
+impl FromStr for PixelAddChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, args) = s.split_once('=').unwrap_or((s, ""));
+        let args: Vec<&str> = if args.is_empty() { vec![] } else { args.split(':').collect() };
+        let color = |i: usize, default: [u8; 3]| args.get(i).map_or(Ok(default), |c| parse_hex_color(c));
+        let number = |i: usize, default: f64| args.get(i).map_or(Ok(default), |n| n.parse::<f64>().map_err(|_| format!("Invalid number: {}", n)));
+        match name {
+            "random_pixel" => { Ok(PixelAddChoice::RandomPixel) }
+            "random_red_shade" => { Ok(PixelAddChoice::RandomRedShade) }
+            "random_blue_shade" => { Ok(PixelAddChoice::RandomBlueShade) }
+            "random_green_shade" => { Ok(PixelAddChoice::RandomGreenShade) }
+            "black" => { Ok(PixelAddChoice::Black) }
+            "palette" => { parse_palette(&args) }
+            "linear_gradient" => { Ok(PixelAddChoice::LinearGradient { start: color(0, [0, 0, 0])?, end: color(1, [255, 255, 255])?, angle: number(2, 0.0)? }) }
+            "radial_gradient" => { Ok(PixelAddChoice::RadialGradient { center: color(0, [255, 255, 255])?, edge: color(1, [0, 0, 0])? }) }
+            "perlin_noise" => { Ok(PixelAddChoice::PerlinNoise { scale: number(0, 64.0)? }) }
+            "jitter" => { Ok(PixelAddChoice::Jitter { amount: number(0, 32.0)?.clamp(0.0, 255.0) as u8 }) }
+            "inverted" => { Ok(PixelAddChoice::Inverted) }
+            "channel_rotated" => { Ok(PixelAddChoice::ChannelRotated) }
+            _ => { Err(format!("Unknown pixel addition function: {}", s)) }
+        }
+    }
 }
 
-pub fn get_random_pixel() -> Rgba<u8> {
-    let mut rng = rand::thread_rng();
+// `#rrggbb` colors with an optional `*weight` each, either every color has a weight or none has
+fn parse_palette(args: &[&str]) -> Result<PixelAddChoice, String> {
+    if args.is_empty() {
+        return Err("Palette needs at least one color".to_string());
+    }
+    let mut colors = vec![];
+    let mut weights = vec![];
+    for arg in args {
+        let (color, weight) = match arg.split_once('*') {
+            Some((color, weight)) => { (color, Some(weight.parse::<f64>().ok().filter(|w| *w >= 0.0).ok_or(format!("Invalid palette weight: {}", weight))?)) }
+            None => { (*arg, None) }
+        };
+        colors.push(parse_hex_color(color)?);
+        weights.extend(weight);
+    }
+    if !weights.is_empty() && weights.len() != colors.len() {
+        return Err("Either every palette color needs a weight or none".to_string());
+    }
+    Ok(PixelAddChoice::Palette { colors, weights })
+}
+
+impl fmt::Display for PixelAddChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PixelAddChoice::RandomPixel => { write!(f, "random_pixel") }
+            PixelAddChoice::RandomRedShade => { write!(f, "random_red_shade") }
+            PixelAddChoice::RandomBlueShade => { write!(f, "random_blue_shade") }
+            PixelAddChoice::RandomGreenShade => { write!(f, "random_green_shade") }
+            PixelAddChoice::Black => { write!(f, "black") }
+            PixelAddChoice::Palette { colors, weights } => {
+                let entries: Vec<String> = colors.iter().enumerate().map(|(i, color)| match weights.get(i) {
+                    Some(weight) if weights.len() == colors.len() => { format!("{}*{}", format_hex_color(color), weight) }
+                    _ => { format_hex_color(color) }
+                }).collect();
+                write!(f, "palette={}", entries.join(":"))
+            }
+            PixelAddChoice::LinearGradient { start, end, angle } => { write!(f, "linear_gradient={}:{}:{}", format_hex_color(start), format_hex_color(end), angle) }
+            PixelAddChoice::RadialGradient { center, edge } => { write!(f, "radial_gradient={}:{}", format_hex_color(center), format_hex_color(edge)) }
+            PixelAddChoice::PerlinNoise { scale } => { write!(f, "perlin_noise={}", scale) }
+            PixelAddChoice::Jitter { amount } => { write!(f, "jitter={}", amount) }
+            PixelAddChoice::Inverted => { write!(f, "inverted") }
+            PixelAddChoice::ChannelRotated => { write!(f, "channel_rotated") }
+        }
+    }
+}
+
+impl TryFrom<String> for PixelAddChoice {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<PixelAddChoice> for String {
+    fn from(pixel_add_choice: PixelAddChoice) -> Self {
+        pixel_add_choice.to_string()
+    }
+}
+
+pub fn get_random_pixel<R: Rng>(rng: &mut R) -> Rgba<u8> {
     Rgba([
-        rng.gen_range(0..=255) as u8,
-        rng.gen_range(0..=255) as u8,
-        rng.gen_range(0..=255) as u8,
+        rng.random_range(0..=255) as u8,
+        rng.random_range(0..=255) as u8,
+        rng.random_range(0..=255) as u8,
         255]
     )
 }
 
-pub fn get_random_red_shade() -> Rgba<u8> {
-    let mut rng = rand::thread_rng();
+pub fn get_random_red_shade<R: Rng>(rng: &mut R) -> Rgba<u8> {
     Rgba([
-        rng.gen_range(0..=255) as u8,
+        rng.random_range(0..=255) as u8,
         0_u8,
         0_u8,
         255]
     )
 }
 
-pub fn get_random_blue_shade() -> Rgba<u8> {
-    let mut rng = rand::thread_rng();
+pub fn get_random_blue_shade<R: Rng>(rng: &mut R) -> Rgba<u8> {
     Rgba([
         0_u8,
         0_u8,
-        rng.gen_range(0..=255) as u8,
+        rng.random_range(0..=255) as u8,
         255]
     )
 }
 
-pub fn get_random_green_shade() -> Rgba<u8> {
-    let mut rng = rand::thread_rng();
+pub fn get_random_green_shade<R: Rng>(rng: &mut R) -> Rgba<u8> {
     Rgba([
         0_u8,
-        rng.gen_range(0..=255) as u8,
+        rng.random_range(0..=255) as u8,
         0_u8,
         255]
     )
@@ -58,4 +183,171 @@ pub fn get_black() -> Rgba<u8> {
         0_u8,
         255]
     )
-}
\ No newline at end of file
+}
+
+fn mix(from: &[u8; 3], to: &[u8; 3], t: f64) -> Rgba<u8> {
+    let t = t.clamp(0.0, 1.0);
+    let channel = |i: usize| (from[i] as f64 + (to[i] as f64 - from[i] as f64) * t).round() as u8;
+    Rgba([channel(0), channel(1), channel(2), 255])
+}
+
+pub fn get_linear_gradient(x: u32, y: u32, (width, height): (u32, u32), start: &[u8; 3], end: &[u8; 3], angle: f64) -> Rgba<u8> {
+    let (sin, cos) = angle.to_radians().sin_cos();
+    let (dx, dy) = (x as f64 - width as f64 / 2.0, y as f64 - height as f64 / 2.0);
+    // Distance from the center to the farthest corner along the gradient
+    let extent = (width as f64 / 2.0 * cos.abs() + height as f64 / 2.0 * sin.abs()).max(1.0);
+    mix(start, end, ((dx * cos + dy * sin) / extent + 1.0) / 2.0)
+}
+
+pub fn get_radial_gradient(x: u32, y: u32, (width, height): (u32, u32), center: &[u8; 3], edge: &[u8; 3]) -> Rgba<u8> {
+    let (dx, dy) = (x as f64 - width as f64 / 2.0, y as f64 - height as f64 / 2.0);
+    let radius = (width as f64 / 2.0).hypot(height as f64 / 2.0).max(1.0);
+    mix(center, edge, dx.hypot(dy) / radius)
+}
+
+pub fn get_perlin_noise(x: u32, y: u32, scale: f64) -> Rgba<u8> {
+    let (nx, ny) = (x as f64 / scale.max(1.0), y as f64 / scale.max(1.0));
+    // 2D Perlin noise stays within ±√½
+    let channel = |c: u64| ((perlin(nx, ny, c) / std::f64::consts::SQRT_2 + 0.5).clamp(0.0, 1.0) * 255.0).round() as u8;
+    Rgba([channel(0), channel(1), channel(2), 255])
+}
+
+// Classic gradient noise with the lattice gradients taken from a hash instead of a permutation table
+fn perlin(x: f64, y: f64, channel: u64) -> f64 {
+    let gradient = |ix: i64, iy: i64| {
+        let mut h = (ix as u64).wrapping_mul(0x9E3779B97F4A7C15) ^ (iy as u64).wrapping_mul(0xC2B2AE3D27D4EB4F) ^ channel.wrapping_mul(0x165667B19E3779F9);
+        h ^= h >> 29;
+        h = h.wrapping_mul(0xBF58476D1CE4E5B9);
+        h ^= h >> 32;
+        let angle = (h as f64 / u64::MAX as f64) * std::f64::consts::TAU;
+        (angle.cos(), angle.sin())
+    };
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let corner = |cx: f64, cy: f64| {
+        let (gx, gy) = gradient((x0 + cx) as i64, (y0 + cy) as i64);
+        gx * (fx - cx) + gy * (fy - cy)
+    };
+    let fade = |t: f64| t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+    let (u, v) = (fade(fx), fade(fy));
+    let top = corner(0.0, 0.0) + u * (corner(1.0, 0.0) - corner(0.0, 0.0));
+    let bottom = corner(0.0, 1.0) + u * (corner(1.0, 1.0) - corner(0.0, 1.0));
+    top + v * (bottom - top)
+}
+
+pub fn get_jittered<R: Rng>(original: Rgba<u8>, amount: u8, rng: &mut R) -> Rgba<u8> {
+    let amount = amount as i16;
+    let channel = |c: u8, rng: &mut R| (c as i16 + rng.random_range(-amount..=amount)).clamp(0, 255) as u8;
+    Rgba([channel(original.0[0], rng), channel(original.0[1], rng), channel(original.0[2], rng), original.0[3]])
+}
+
+pub fn get_inverted(original: Rgba<u8>) -> Rgba<u8> {
+    Rgba([255 - original.0[0], 255 - original.0[1], 255 - original.0[2], original.0[3]])
+}
+
+pub fn get_channel_rotated(original: Rgba<u8>) -> Rgba<u8> {
+    Rgba([original.0[2], original.0[0], original.0[1], original.0[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    #[test]
+    fn names_with_arguments() {
+        assert_eq!("black".parse::<PixelAddChoice>(), Ok(PixelAddChoice::Black));
+        assert_eq!("linear_gradient=#ff0000:#0000ff:90".parse::<PixelAddChoice>(), Ok(PixelAddChoice::LinearGradient { start: [255, 0, 0], end: [0, 0, 255], angle: 90.0 }));
+        assert_eq!("linear_gradient=102030".parse::<PixelAddChoice>(), Ok(PixelAddChoice::LinearGradient { start: [16, 32, 48], end: [255, 255, 255], angle: 0.0 }));
+        assert_eq!("linear_gradient".parse::<PixelAddChoice>(), Ok(PixelAddChoice::LinearGradient { start: [0, 0, 0], end: [255, 255, 255], angle: 0.0 }));
+        assert_eq!("radial_gradient=#ffffff:#800000".parse::<PixelAddChoice>(), Ok(PixelAddChoice::RadialGradient { center: [255, 255, 255], edge: [128, 0, 0] }));
+        assert_eq!("perlin_noise=12.5".parse::<PixelAddChoice>(), Ok(PixelAddChoice::PerlinNoise { scale: 12.5 }));
+        assert_eq!("perlin_noise".parse::<PixelAddChoice>(), Ok(PixelAddChoice::PerlinNoise { scale: 64.0 }));
+        assert_eq!("jitter=10".parse::<PixelAddChoice>(), Ok(PixelAddChoice::Jitter { amount: 10 }));
+        assert_eq!("jitter=1000".parse::<PixelAddChoice>(), Ok(PixelAddChoice::Jitter { amount: 255 }));
+    }
+
+    #[test]
+    fn names_round_trip() {
+        let choices = [
+            PixelAddChoice::RandomPixel, PixelAddChoice::RandomRedShade, PixelAddChoice::RandomBlueShade,
+            PixelAddChoice::RandomGreenShade, PixelAddChoice::Black,
+            PixelAddChoice::Palette { colors: vec![[255, 0, 0], [0, 16, 255]], weights: vec![] },
+            PixelAddChoice::Palette { colors: vec![[255, 0, 0], [0, 16, 255]], weights: vec![2.5, 128.0] },
+            PixelAddChoice::LinearGradient { start: [1, 2, 3], end: [250, 251, 252], angle: 33.3 },
+            PixelAddChoice::RadialGradient { center: [255, 255, 255], edge: [0, 0, 0] },
+            PixelAddChoice::PerlinNoise { scale: 12.5 }, PixelAddChoice::Jitter { amount: 7 },
+            PixelAddChoice::Inverted, PixelAddChoice::ChannelRotated,
+        ];
+        for choice in choices {
+            let json = serde_json::to_string(&choice).unwrap();
+            assert_eq!(json, format!("\"{}\"", choice));
+            assert_eq!(choice.to_string().parse::<PixelAddChoice>(), Ok(choice.clone()));
+            assert_eq!(serde_json::from_str::<PixelAddChoice>(&json).unwrap(), choice);
+        }
+        assert_eq!(PixelAddChoice::Palette { colors: vec![[255, 0, 0], [0, 16, 255]], weights: vec![2.5, 1.0] }.to_string(), "palette=#ff0000*2.5:#0010ff*1");
+        assert_eq!(PixelAddChoice::LinearGradient { start: [0, 0, 0], end: [255, 255, 255], angle: 90.0 }.to_string(), "linear_gradient=#000000:#ffffff:90");
+    }
+
+    #[test]
+    fn palettes_with_weights() {
+        assert_eq!("palette=#ff0000:00ff00".parse::<PixelAddChoice>(), Ok(PixelAddChoice::Palette { colors: vec![[255, 0, 0], [0, 255, 0]], weights: vec![] }));
+        assert_eq!("palette=#ff0000*3:#00ff00*0.5".parse::<PixelAddChoice>(), Ok(PixelAddChoice::Palette { colors: vec![[255, 0, 0], [0, 255, 0]], weights: vec![3.0, 0.5] }));
+        for bad in ["palette", "palette=", "palette=#ff0000*2:#00ff00", "palette=#ff0000*heavy", "palette=#ff0000*-1", "palette=#ff0000:red"] {
+            assert!(bad.parse::<PixelAddChoice>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        for bad in ["nope", "Black", "linear_gradient=#gg0000", "linear_gradient=#ff0000:#00ff00:steep", "radial_gradient=#fff", "perlin_noise=big", "jitter=lots"] {
+            assert!(bad.parse::<PixelAddChoice>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn linear_gradient_ends() {
+        let (black, white) = ([0, 0, 0], [255, 255, 255]);
+        // Left to right: the left edge is the start, the center halfway, one pixel past the right edge the end
+        assert_eq!(get_linear_gradient(0, 3, (10, 10), &black, &white, 0.0), Rgba([0, 0, 0, 255]));
+        assert_eq!(get_linear_gradient(5, 3, (10, 10), &black, &white, 0.0), Rgba([128, 128, 128, 255]));
+        assert_eq!(get_linear_gradient(10, 3, (10, 10), &black, &white, 0.0), Rgba([255, 255, 255, 255]));
+        // Diagonal from the top left corner to the bottom right one
+        assert_eq!(get_linear_gradient(0, 0, (10, 10), &black, &white, 45.0), Rgba([0, 0, 0, 255]));
+        assert_eq!(get_linear_gradient(10, 10, (10, 10), &black, &white, 45.0), Rgba([255, 255, 255, 255]));
+        assert_eq!(get_linear_gradient(10, 0, (10, 10), &black, &white, 45.0), Rgba([128, 128, 128, 255]));
+        // Opposite direction swaps the ends
+        assert_eq!(get_linear_gradient(0, 3, (10, 10), &black, &white, 180.0), Rgba([255, 255, 255, 255]));
+    }
+
+    #[test]
+    fn radial_gradient_ends() {
+        let (red, blue) = ([255, 0, 0], [0, 0, 255]);
+        assert_eq!(get_radial_gradient(8, 4, (16, 8), &red, &blue), Rgba([255, 0, 0, 255]));
+        assert_eq!(get_radial_gradient(0, 0, (16, 8), &red, &blue), Rgba([0, 0, 255, 255]));
+        assert_eq!(get_radial_gradient(16, 8, (16, 8), &red, &blue), Rgba([0, 0, 255, 255]));
+    }
+
+    #[test]
+    fn generators_from_the_original_pixel() {
+        let original = Rgba([10, 100, 200, 77]);
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        assert_eq!(PixelAddChoice::Inverted.get_pixel(0, 0, original, (1, 1), &mut rng), Rgba([245, 155, 55, 77]));
+        assert_eq!(PixelAddChoice::ChannelRotated.get_pixel(0, 0, original, (1, 1), &mut rng), Rgba([200, 10, 100, 77]));
+        assert_eq!(PixelAddChoice::Jitter { amount: 0 }.get_pixel(0, 0, original, (1, 1), &mut rng), original);
+        for _ in 0..100 {
+            let p = PixelAddChoice::Jitter { amount: 5 }.get_pixel(0, 0, original, (1, 1), &mut rng);
+            assert!(p.0.iter().zip(original.0).all(|(a, b)| a.abs_diff(b) <= 5));
+            assert_eq!(p.0[3], 77);
+        }
+    }
+
+    #[test]
+    fn perlin_noise_is_gray_on_the_lattice() {
+        assert_eq!(get_perlin_noise(0, 0, 16.0), Rgba([128, 128, 128, 255]));
+        assert_eq!(get_perlin_noise(32, 48, 16.0), Rgba([128, 128, 128, 255]));
+        assert_eq!(get_perlin_noise(7, 9, 16.0), get_perlin_noise(7, 9, 16.0));
+        assert_ne!(get_perlin_noise(7, 9, 16.0), Rgba([128, 128, 128, 255]));
+    }
+}
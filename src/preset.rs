@@ -0,0 +1,102 @@
+use std::fs;
+use std::path::Path;
+
+use crate::{EffectParams, MaskParams};
+use crate::mask::{MaskFuncChoice, MaskRule};
+use crate::pixel::{ColorValueChoice, PixelSortKeyChoice};
+use crate::pixel_generators::PixelAddChoice;
+use crate::sort_effect::{SortDirectionChoice, SortOptions, SortOrderChoice};
+
+// Presets are stored as pretty printed JSON of `EffectParams`, missing fields fall back to defaults
+pub fn save_preset(params: &EffectParams, path: &Path) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(params).map_err(|e| e.to_string())?;
+    fs::write(path, json).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+pub fn load_preset(path: &Path) -> Result<EffectParams, String> {
+    let json = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    serde_json::from_str(&json).map_err(|e| format!("Invalid preset {}: {}", path.display(), e))
+}
+
+pub fn builtin_presets() -> Vec<(&'static str, EffectParams)> {
+    vec![
+        ("Default", EffectParams::default()),
+        ("Bright noise", EffectParams {
+            mask: MaskParams { mask_rule: MaskRule { low_threshold: 140.0, high_threshold: 190.0, ..Default::default() }, ..Default::default() },
+            random_prob: 0.31,
+            ..Default::default()
+        }),
+        ("Vertical drips", EffectParams {
+            mask: MaskParams { mask_rule: MaskRule { low_threshold: 90.0, ..Default::default() }, ..Default::default() },
+            random_prob: 0.0,
+            pixel_sort_choice: PixelSortKeyChoice::Luminance,
+            sort_options: SortOptions {
+                direction: SortDirectionChoice::Vertical,
+                order: SortOrderChoice::Descending,
+                ..Default::default()
+            },
+            ..Default::default()
+        }),
+        ("Shadow fragments", EffectParams {
+            mask: MaskParams { mask_rule: MaskRule { low_threshold: 0.0, high_threshold: 80.0, ..Default::default() }, ..Default::default() },
+            random_prob: 0.05,
+            pixel_add_choice: PixelAddChoice::Black,
+            pixel_sort_choice: PixelSortKeyChoice::ColorSum,
+            sort_options: SortOptions {
+                order: SortOrderChoice::RandomPerSpan,
+                max_span_length: 60,
+                random_span_length: true,
+                min_span_length: 10,
+                ..Default::default()
+            },
+            ..Default::default()
+        }),
+        ("Edge intervals", EffectParams {
+            mask: MaskParams {
+                mask_rule: MaskRule {
+                    mask_func_choice: MaskFuncChoice::SobelEdges,
+                    low_threshold: 20.0,
+                    high_threshold: 60.0,
+                    invert_mask: true,
+                    edge_hysteresis: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            random_prob: 0.0,
+            pixel_sort_choice: PixelSortKeyChoice::Luminance,
+            ..Default::default()
+        }),
+        ("Hue slices", EffectParams {
+            mask: MaskParams { mask_rule: MaskRule { mask_func_choice: MaskFuncChoice::Color(ColorValueChoice::Hue), low_threshold: 160.0, high_threshold: 280.0, ..Default::default() }, ..Default::default() },
+            random_prob: 0.0,
+            sort_options: SortOptions {
+                direction: SortDirectionChoice::Angle,
+                angle: 30.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        }),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn broken_hue_presets_still_load() {
+        let params: EffectParams = serde_json::from_str(r#"{"mask_func_choice": "broken_hue", "pixel_sort_choice": "broken_hue"}"#).unwrap();
+        assert_eq!(params.mask.mask_rule.mask_func_choice, MaskFuncChoice::Color(ColorValueChoice::QuantizedHue { buckets: 3 }));
+        assert_eq!(params.pixel_sort_choice, PixelSortKeyChoice::QuantizedHue { buckets: 3 });
+        assert_eq!(serde_json::to_value(&params).unwrap()["pixel_sort_choice"], "quantized_hue=3");
+    }
+
+    #[test]
+    fn builtin_presets_round_trip() {
+        for (name, params) in builtin_presets() {
+            let json = serde_json::to_string(&params).unwrap();
+            assert!(serde_json::from_str::<EffectParams>(&json).unwrap() == params, "{}", name);
+        }
+    }
+}
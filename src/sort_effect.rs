@@ -1,50 +1,468 @@
-use image::{ImageBuffer, Luma, Pixel, Rgba};
-use rand::Rng;
+use std::cmp::Reverse;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use image::{ImageBuffer, Luma, Rgba};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
-fn pixel_matrix(image: &ImageBuffer::<Rgba<u8>, Vec<u8>>) -> Vec<Vec<&Rgba<u8>>> {
-    image.rows().map(|r| r.collect()).collect()
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortModeChoice {
+    Interval,
+    WholeRow,
 }
 
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortDirectionChoice {
+    Horizontal,
+    Vertical,
+    DiagonalDown,
+    DiagonalUp,
+    Angle,
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrderChoice {
+    Ascending,
+    Descending,
+    RandomPerSpan,
+}
+
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct SortOptions {
+    pub mode: SortModeChoice,
+    pub direction: SortDirectionChoice,
+    // Used by `SortDirectionChoice::Angle`
+    pub angle: f64,
+    pub order: SortOrderChoice,
+    // 0 means spans are never broken
+    pub max_span_length: usize,
+    // Chunk lengths are picked from `min_span_length..=max_span_length` when enabled
+    pub random_span_length: bool,
+    pub min_span_length: usize,
+}
+
+// Shared between the sorting job and whoever waits for it
+#[derive(Default)]
+pub struct Progress {
+    done_lines: AtomicUsize,
+    total_lines: AtomicUsize,
+    cancelled: AtomicBool,
+}
+
+impl Progress {
+    pub fn fraction(&self) -> f32 {
+        let total = self.total_lines.load(Ordering::Relaxed);
+        if total == 0 { 0.0 } else { self.done_lines.load(Ordering::Relaxed) as f32 / total as f32 }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for SortOptions {
+    fn default() -> Self {
+        Self {
+            mode: SortModeChoice::Interval,
+            direction: SortDirectionChoice::Horizontal,
+            angle: 30.0,
+            order: SortOrderChoice::Ascending,
+            max_span_length: 0,
+            random_span_length: false,
+            min_span_length: 1,
+        }
+    }
+}
+
+impl SortDirectionChoice {
+    // Angle in degrees, clockwise from "left to right" (image y axis points down)
+    pub fn get_angle(&self, custom_angle: f64) -> f64 {
+        match self {
+            SortDirectionChoice::Horizontal => { 0.0 }
+            SortDirectionChoice::Vertical => { 90.0 }
+            SortDirectionChoice::DiagonalDown => { 45.0 }
+            SortDirectionChoice::DiagonalUp => { -45.0 }
+            SortDirectionChoice::Angle => { custom_angle }
+        }
+    }
+}
+
+impl FromStr for SortModeChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "interval" => { Ok(SortModeChoice::Interval) }
+            "whole_row" => { Ok(SortModeChoice::WholeRow) }
+            _ => { Err(format!("Unknown sorting mode: {}", s)) }
+        }
+    }
+}
+
+impl FromStr for SortDirectionChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "horizontal" => { Ok(SortDirectionChoice::Horizontal) }
+            "vertical" => { Ok(SortDirectionChoice::Vertical) }
+            "diagonal_down" => { Ok(SortDirectionChoice::DiagonalDown) }
+            "diagonal_up" => { Ok(SortDirectionChoice::DiagonalUp) }
+            "angle" => { Ok(SortDirectionChoice::Angle) }
+            _ => { Err(format!("Unknown sorting direction: {}", s)) }
+        }
+    }
+}
+
+impl FromStr for SortOrderChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ascending" => { Ok(SortOrderChoice::Ascending) }
+            "descending" => { Ok(SortOrderChoice::Descending) }
+            "random_per_span" => { Ok(SortOrderChoice::RandomPerSpan) }
+            _ => { Err(format!("Unknown sorting order: {}", s)) }
+        }
+    }
+}
+
+// Rasterises parallel lines going in the given direction so that every pixel of the image
+// belongs to exactly one line. Pixels of a line are ordered in the sorting direction.
+fn sort_lines(width: u32, height: u32, angle: f64) -> Vec<Vec<(u32, u32)>> {
+    let (dy, dx) = angle.to_radians().sin_cos();
+    let (width, height) = (width as i64, height as i64);
+
+    // Walk along the major axis, the minor coordinate is `k + round(t * slope)`
+    let (major_len, minor_len, slope, reversed, swap) = if dx.abs() >= dy.abs() {
+        (width, height, dy / dx, dx < 0.0, false)
+    } else {
+        (height, width, dx / dy, dy < 0.0, true)
+    };
+    let offsets: Vec<i64> = (0..major_len).map(|t| (t as f64 * slope).round() as i64).collect();
+    let (min_offset, max_offset) = (*offsets.iter().min().unwrap_or(&0), *offsets.iter().max().unwrap_or(&0));
+
+    ((-max_offset)..(minor_len - min_offset)).map(|k| {
+        let mut line: Vec<(u32, u32)> = offsets.iter().enumerate()
+            .filter(|(_, o)| (0..minor_len).contains(&(k + **o)))
+            .map(|(t, o)| if swap { ((k + o) as u32, t as u32) } else { (t as u32, (k + o) as u32) })
+            .collect();
+        if reversed {
+            line.reverse();
+        }
+        line
+    }).filter(|l| !l.is_empty()).collect()
+}
+
+// Gives every line its own deterministic generator, so results do not depend on rayon scheduling.
+// Separate streams keep e.g. span breaking unchanged when only the pixel addition probability changes.
+// ChaCha8 is a fixed algorithm, unlike `StdRng` which may change between rand versions.
+fn line_rng(seed: u64, line_index: usize, stream: u64) -> ChaCha8Rng {
+    ChaCha8Rng::seed_from_u64(seed ^ (line_index as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) ^ stream.wrapping_mul(0xD1B5_4A32_D192_ED03))
+}
+
+// Mixes the original and the sorted pixel according to a soft mask value
+fn blend(original: &Rgba<u8>, sorted: &Rgba<u8>, weight: u8) -> Rgba<u8> {
+    let w = weight as f32 / 255.0;
+    Rgba(std::array::from_fn(|c| (sorted.0[c] as f32 * w + original.0[c] as f32 * (1.0 - w)).round() as u8))
+}
+
+// Splits the masked positions of a line into the groups that get sorted together
+fn split_spans(masked: Vec<usize>, sort_mode: &SortModeChoice) -> Vec<Vec<usize>> {
+    match sort_mode {
+        SortModeChoice::WholeRow => { if masked.is_empty() { vec![] } else { vec![masked] } }
+        SortModeChoice::Interval => {
+            let mut spans: Vec<Vec<usize>> = vec![];
+            for i in masked {
+                match spans.last_mut() {
+                    Some(span) if *span.last().unwrap() + 1 == i => { span.push(i) }
+                    _ => { spans.push(vec![i]) }
+                }
+            }
+            spans
+        }
+    }
+}
+
+// Cuts spans longer than the allowed length into chunks that are sorted separately
+fn break_spans(spans: Vec<Vec<usize>>, sort_options: &SortOptions, rng: &mut ChaCha8Rng) -> Vec<Vec<usize>> {
+    if sort_options.max_span_length == 0 {
+        return spans;
+    }
+    let max_len = sort_options.max_span_length;
+    let min_len = sort_options.min_span_length.clamp(1, max_len);
+
+    let mut chunks: Vec<Vec<usize>> = vec![];
+    for span in spans {
+        let mut rest: &[usize] = &span;
+        while !rest.is_empty() {
+            let len = if sort_options.random_span_length { rng.random_range(min_len..=max_len) } else { max_len };
+            let (chunk, tail) = rest.split_at(len.min(rest.len()));
+            chunks.push(chunk.to_vec());
+            rest = tail;
+        }
+    }
+    chunks
+}
+
+// Float sort key ordered with `total_cmp`, so NaN can't break the sort
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+pub struct SortKey(pub f64);
+
+impl PartialEq for SortKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl Eq for SortKey {}
+
+impl PartialOrd for SortKey {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SortKey {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+// Returns `None` when the job was cancelled through `progress`
+#[allow(clippy::too_many_arguments)]
 pub fn process_sorting_effect<
-    PA: Fn(usize, usize, Rgba<u8>) -> Rgba<u8> + Sync + Send,
-    PF: Fn(&Rgba<u8>) -> i16 + Sync + Send
+    PA: Fn(u32, u32, Rgba<u8>, &mut ChaCha8Rng) -> Rgba<u8> + Sync + Send,
+    K: Ord,
+    PF: Fn(&Rgba<u8>) -> K + Sync + Send
 >(
     image: &ImageBuffer::<Rgba<u8>, Vec<u8>>,
     mask_image: &ImageBuffer::<Luma<u8>, Vec<u8>>,
     pixel_add_random_prob: f64,
     pixel_add_func: PA,
-    pixel_sort_key_func: PF
-) -> ImageBuffer::<Rgba<u8>, Vec<u8>>
+    pixel_sort_key_func: PF,
+    sort_options: &SortOptions,
+    seed: u64,
+    progress: &Progress,
+) -> Option<ImageBuffer::<Rgba<u8>, Vec<u8>>>
 {
     let (width, height) = image.dimensions();
 
-    let rows: Vec<Vec<&Rgba<u8>>> = pixel_matrix(&image);
-    let new_rows: Vec<Vec<Rgba<u8>>> = rows.into_par_iter().enumerate()
-        .map(|(y, row)| {
-            let mut rng = rand::thread_rng();
-            let re: Vec<(usize, usize, Rgba<u8>)> = row.iter().enumerate()
-                .filter(|(x, _)| mask_image.get_pixel(x.clone() as u32, y as u32).0[0] == 255)
-                .enumerate()
-                .map(|(x, e)| (x, e.0, **e.1))
-                .collect();
-            let mut r: Vec<Rgba<u8>> = re.into_iter().map(| (x, y, p) | if rng.gen_bool(pixel_add_random_prob) {  pixel_add_func(x, y, p) } else { p }).collect();
-            r.par_sort_by_key(|p: &Rgba<u8>| pixel_sort_key_func(&p));
-            r
+    let lines: Vec<Vec<(u32, u32)>> = sort_lines(width, height, sort_options.direction.get_angle(sort_options.angle));
+    progress.done_lines.store(0, Ordering::Relaxed);
+    progress.total_lines.store(lines.len(), Ordering::Relaxed);
+    let new_lines: Vec<Vec<(u32, u32, Rgba<u8>)>> = lines.into_par_iter().enumerate()
+        .map(|(line_index, line)| {
+            if progress.is_cancelled() {
+                return vec![];
+            }
+            let mut rng = line_rng(seed, line_index, 0);
+            let mut span_rng = line_rng(seed, line_index, 1);
+            let mut new_line: Vec<Rgba<u8>> = line.iter().map(|(x, y)| *image.get_pixel(*x, *y)).collect();
+            let weights: Vec<u8> = line.iter().map(|(x, y)| mask_image.get_pixel(*x, *y).0[0]).collect();
+            let masked: Vec<usize> = (0..line.len()).filter(|i| weights[*i] > 0).collect();
+            let spans = split_spans(masked, &sort_options.mode);
+            for span in break_spans(spans, sort_options, &mut span_rng) {
+                let mut r: Vec<Rgba<u8>> = span.iter()
+                    .map(|t| if rng.random_bool(pixel_add_random_prob) { pixel_add_func(line[*t].0, line[*t].1, new_line[*t], &mut rng) } else { new_line[*t] })
+                    .collect();
+                let descending = match sort_options.order {
+                    SortOrderChoice::Ascending => { false }
+                    SortOrderChoice::Descending => { true }
+                    SortOrderChoice::RandomPerSpan => { span_rng.random_bool(0.5) }
+                };
+                if descending {
+                    r.sort_by_cached_key(|p: &Rgba<u8>| Reverse(pixel_sort_key_func(p)));
+                } else {
+                    r.sort_by_cached_key(|p: &Rgba<u8>| pixel_sort_key_func(p));
+                }
+                for (t, p) in span.into_iter().zip(r) {
+                    new_line[t] = p;
+                }
+            }
+            for (t, weight) in weights.into_iter().enumerate() {
+                if 0 < weight && weight < 255 {
+                    new_line[t] = blend(image.get_pixel(line[t].0, line[t].1), &new_line[t], weight);
+                }
+            }
+            progress.done_lines.fetch_add(1, Ordering::Relaxed);
+            line.into_iter().zip(new_line).map(|((x, y), p)| (x, y, p)).collect()
         })
         .collect();
 
-    let mut sorted_pixels: Vec<Rgba<u8>> = Vec::with_capacity((width * height) as usize);
-    let mut new_rows_iter = new_rows.iter().flatten();
-    for y in 0..height {
-        for x in 0..width {
-            if mask_image.get_pixel(x, y).0[0] == 255 {
-                sorted_pixels.push(*new_rows_iter.next().unwrap())
-            } else {
-                sorted_pixels.push(*image.get_pixel(x, y))
+    if progress.is_cancelled() {
+        return None;
+    }
+    let mut result = image.clone();
+    for (x, y, p) in new_lines.into_iter().flatten() {
+        result.put_pixel(x, y, p);
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn full_mask_sort(image: &ImageBuffer::<Rgba<u8>, Vec<u8>>, angle: f64) -> ImageBuffer::<Rgba<u8>, Vec<u8>> {
+        let (width, height) = image.dimensions();
+        let mask = ImageBuffer::<Luma<u8>, Vec<u8>>::from_pixel(width, height, Luma([255]));
+        let sort_options = SortOptions { direction: SortDirectionChoice::Angle, angle, ..SortOptions::default() };
+        process_sorting_effect(
+            image, &mask, 0.0, |_, _, p, _| p, |p| p.0[0], &sort_options, 0, &Progress::default(),
+        ).unwrap()
+    }
+
+    // Sorts a single row by the red channel, pixels with a 0 in `mask` stay in place
+    fn sort_row(values: &[u8], mask: &[u8], sort_options: &SortOptions, seed: u64) -> Vec<u8> {
+        let image = ImageBuffer::<Rgba<u8>, Vec<u8>>::from_fn(values.len() as u32, 1, |x, _| Rgba([values[x as usize], 0, 0, 255]));
+        let mask = ImageBuffer::<Luma<u8>, Vec<u8>>::from_fn(values.len() as u32, 1, |x, _| Luma([if mask[x as usize] > 0 { 255 } else { 0 }]));
+        process_sorting_effect(&image, &mask, 0.0, |_, _, p, _| p, |p| p.0[0], sort_options, seed, &Progress::default())
+            .unwrap().pixels().map(|p| p.0[0]).collect()
+    }
+
+    #[test]
+    fn intervals_are_sorted_separately() {
+        let values = [200, 100, 0, 50, 10];
+        let mask = [1, 1, 0, 1, 1];
+        let interval = SortOptions { mode: SortModeChoice::Interval, ..SortOptions::default() };
+        assert_eq!(sort_row(&values, &mask, &interval, 0), vec![100, 200, 0, 10, 50]);
+        let whole_row = SortOptions { mode: SortModeChoice::WholeRow, ..SortOptions::default() };
+        assert_eq!(sort_row(&values, &mask, &whole_row, 0), vec![10, 50, 0, 100, 200]);
+    }
+
+    #[test]
+    fn unmasked_pixels_stay_in_place() {
+        let values = [9, 8, 7, 6, 5, 4, 3, 2, 1];
+        let mask = [0, 1, 1, 0, 0, 1, 0, 1, 1];
+        assert_eq!(sort_row(&values, &mask, &SortOptions::default(), 0), vec![9, 7, 8, 6, 5, 4, 3, 1, 2]);
+        assert_eq!(sort_row(&values, &[0; 9], &SortOptions::default(), 0), values.to_vec());
+    }
+
+    #[test]
+    fn descending_reverses_ascending() {
+        let values = [40, 7, 250, 0, 99, 13, 180];
+        let mask = [1, 1, 1, 0, 1, 1, 1];
+        let ascending = SortOptions { order: SortOrderChoice::Ascending, ..SortOptions::default() };
+        let descending = SortOptions { order: SortOrderChoice::Descending, ..SortOptions::default() };
+        assert_eq!(sort_row(&values, &mask, &ascending, 0), vec![7, 40, 250, 0, 13, 99, 180]);
+        assert_eq!(sort_row(&values, &mask, &descending, 0), vec![250, 40, 7, 0, 180, 99, 13]);
+    }
+
+    #[test]
+    fn random_per_span_picks_a_direction_per_span() {
+        let values: Vec<u8> = (0..60).map(|i| ((i * 37) % 251) as u8).collect();
+        let mask: Vec<u8> = (0..60).map(|i| if i % 6 == 5 { 0 } else { 1 }).collect();
+        let sort_options = SortOptions { order: SortOrderChoice::RandomPerSpan, ..SortOptions::default() };
+        let (mut ascending, mut descending) = (0, 0);
+        for seed in 0..10 {
+            let sorted = sort_row(&values, &mask, &sort_options, seed);
+            assert_eq!(sorted, sort_row(&values, &mask, &sort_options, seed));
+            for span in sorted.chunks(6).map(|c| &c[..5]) {
+                if span.windows(2).all(|w| w[0] <= w[1]) {
+                    ascending += 1;
+                } else {
+                    assert!(span.windows(2).all(|w| w[0] >= w[1]), "{:?}", span);
+                    descending += 1;
+                }
+            }
+        }
+        assert!(ascending > 0 && descending > 0);
+    }
+
+    #[test]
+    fn spans_are_broken_at_the_max_length() {
+        let spans = vec![(0..23).collect::<Vec<usize>>(), vec![30], (40..47).collect()];
+        let sort_options = SortOptions { max_span_length: 5, ..SortOptions::default() };
+        let chunks = break_spans(spans.clone(), &sort_options, &mut line_rng(0, 0, 1));
+        let lengths: Vec<usize> = chunks.iter().map(|c| c.len()).collect();
+        assert_eq!(lengths, vec![5, 5, 5, 5, 3, 1, 5, 2]);
+        assert_eq!(chunks.concat(), spans.concat());
+
+        let unbroken = SortOptions { max_span_length: 0, ..SortOptions::default() };
+        assert_eq!(break_spans(spans.clone(), &unbroken, &mut line_rng(0, 0, 1)), spans);
+    }
+
+    #[test]
+    fn random_span_lengths_stay_in_range() {
+        let span: Vec<usize> = (0..1000).collect();
+        for (min_span_length, max_span_length) in [(3, 7), (1, 1), (4, 4), (10, 2)] {
+            let sort_options = SortOptions { max_span_length, min_span_length, random_span_length: true, ..SortOptions::default() };
+            for seed in 0..20 {
+                let chunks = break_spans(vec![span.clone()], &sort_options, &mut line_rng(seed, 0, 1));
+                assert_eq!(chunks.concat(), span);
+                let (last, rest) = chunks.split_last().unwrap();
+                let min_len = min_span_length.min(max_span_length);
+                assert!(rest.iter().all(|c| (min_len..=max_span_length).contains(&c.len())), "{}..={}", min_span_length, max_span_length);
+                assert!((1..=max_span_length).contains(&last.len()));
             }
         }
     }
 
-    ImageBuffer::<Rgba<u8>, Vec<u8>>::from_vec(width, height, sorted_pixels.iter().flat_map(|p| p.channels().to_vec()).collect()).unwrap()
-}
\ No newline at end of file
+    #[test]
+    fn sorted_chunks_respect_the_max_length() {
+        let values = [9, 8, 7, 6, 5, 4, 3];
+        let sort_options = SortOptions { max_span_length: 3, ..SortOptions::default() };
+        assert_eq!(sort_row(&values, &[1; 7], &sort_options, 0), vec![7, 8, 9, 4, 5, 6, 3]);
+    }
+
+    #[test]
+    fn sort_lines_cover_every_pixel_once() {
+        let angles = [0.0, 13.0, 30.0, 45.0, 60.0, 90.0, 135.0, 180.0, 225.0, 270.0, 359.0, -1.0, -45.0, -90.0, -200.0, 720.0];
+        for (width, height) in [(1, 1), (1, 9), (9, 1), (17, 5), (6, 23), (32, 32)] {
+            for angle in angles {
+                let mut seen = vec![0; (width * height) as usize];
+                for (x, y) in sort_lines(width, height, angle).into_iter().flatten() {
+                    assert!(x < width && y < height, "({}, {}) outside {}x{} at {} degrees", x, y, width, height, angle);
+                    seen[(y * width + x) as usize] += 1;
+                }
+                assert!(seen.iter().all(|c| *c == 1), "{}x{} at {} degrees", width, height, angle);
+            }
+        }
+    }
+
+    #[test]
+    fn sort_lines_follow_the_direction() {
+        assert_eq!(sort_lines(3, 2, 0.0), vec![vec![(0, 0), (1, 0), (2, 0)], vec![(0, 1), (1, 1), (2, 1)]]);
+        assert_eq!(sort_lines(3, 2, 180.0), vec![vec![(2, 0), (1, 0), (0, 0)], vec![(2, 1), (1, 1), (0, 1)]]);
+        assert_eq!(sort_lines(2, 3, 90.0), vec![vec![(0, 0), (0, 1), (0, 2)], vec![(1, 0), (1, 1), (1, 2)]]);
+        assert_eq!(sort_lines(2, 3, 270.0), vec![vec![(0, 2), (0, 1), (0, 0)], vec![(1, 2), (1, 1), (1, 0)]]);
+        assert!(sort_lines(4, 4, 45.0).contains(&vec![(0, 0), (1, 1), (2, 2), (3, 3)]));
+        assert!(sort_lines(4, 4, -45.0).contains(&vec![(0, 3), (1, 2), (2, 1), (3, 0)]));
+    }
+
+    #[test]
+    fn full_mask_sort_is_a_permutation() {
+        let image = ImageBuffer::<Rgba<u8>, Vec<u8>>::from_fn(19, 11, |x, y| Rgba([((x * 37 + y * 91) % 256) as u8, x as u8, y as u8, 255]));
+        let mut original: Vec<[u8; 4]> = image.pixels().map(|p| p.0).collect();
+        original.sort();
+        for angle in [0.0, 45.0, 90.0, 180.0, 270.0, -30.0, 100.0] {
+            let mut sorted: Vec<[u8; 4]> = full_mask_sort(&image, angle).pixels().map(|p| p.0).collect();
+            sorted.sort();
+            assert_eq!(sorted, original, "{} degrees", angle);
+        }
+    }
+}